@@ -1,76 +1,54 @@
+#[cfg(feature = "sdk")]
 extern crate bindgen;
 use std::env;
+#[cfg(feature = "sdk")]
 use std::path::PathBuf;
+#[cfg(feature = "sdk")]
 use std::path::Path;
 use anyhow::Result;
 
+/// Finding the SDK to build against
+#[cfg(feature = "sdk")]
+mod sdk;
 
+
+#[cfg(feature = "sdk")]
 const LIBV5_LIBRARY: &str = "build/libv5rt";
 
+#[cfg(feature = "sdk")]
 const LIBS: [&str; 3] = ["c","m","v5rt"];
 
 
 
 fn main() -> Result<()>{
 
-    // Get the directory to place data in
-    let data_dir = dirs::home_dir().unwrap();
-    let data_dir = data_dir.join(".v5");
-
-    // If it doesn't exist, create it
-    if !data_dir.exists() {
-        std::fs::create_dir(&data_dir)?;
+    // Host builds, such as the test suite, use the host hardware abstraction instead of the SDK
+    if env::var("CARGO_CFG_TARGET_ARCH")? != "arm" {
+        return Ok(());
     }
 
-    // Make the path to the libv5_dir
-    let libv5_dir = data_dir.join("libv5rt");
+    // Without the sdk feature there is nothing to generate or link
+    #[cfg(feature = "sdk")]
+    build_sdk()?;
 
-    // If the path the the libv5 dir does not exist then create it
-    if !libv5_dir.exists() {
-        std::fs::create_dir_all(&libv5_dir)?;
-    }
+    Ok(())
+}
 
-    
-
-    // If the .v5init does not exist in the data directory
-    // we need to install the required libraries
-    let init_file = data_dir.join(".v5init");
-    if !init_file.exists() {
-        // Get the LIBV5_PATH environment variable
-        let libv5_path = std::env::var("LIBV5_PATH");
-
-        // If it is none, we can not upload
-        if libv5_path.is_err() {
-            return Err(anyhow::anyhow!("Could not find LIBV5_PATH environment variable"));
-        }
-
-        let libv5_path = libv5_path.unwrap();
-
-        // Make the path to the libv5_dir
-        let libv5_dir = data_dir.join("libv5rt");
-        
-        // If it does not exist, create it
-        if !libv5_dir.exists() {
-            std::fs::create_dir_all(&libv5_dir)?;
-        }
-
-        // Copy all files from libv5_path to the data directory
-        let mut co = fs_extra::dir::CopyOptions::new();
-        co.content_only = true;
-        fs_extra::dir::copy(libv5_path, libv5_dir, &co)?;
-
-        // Create the .v5init file
-        std::fs::write(&init_file, "")?;
-    }
+/// Finds the SDK, links libv5rt and generates its bindings
+#[cfg(feature = "sdk")]
+fn build_sdk() -> Result<()> {
 
     // Get the build directory
     let build_dir = std::env::var("CARGO_MANIFEST_DIR")?;
     let build_dir = Path::new(&build_dir);
-    
+
+    // Choose the SDK version to build against
+    let sdk = sdk::select()?;
+
     // Find the absolute path to all required files and directories
-    let libv5_library = build_dir.join(libv5_dir.clone()).join("sdk/vexv5");
-    let libv5_include_library = build_dir.join(libv5_dir.clone()).join("sdk/vexv5/include");
-    let gcc_include = build_dir.join(libv5_dir).join("sdk/vexv5/gcc/include");
+    let libv5_library = sdk.library_dir();
+    let libv5_include_library = sdk.include_dir();
+    let gcc_include = sdk.gcc_include_dir();
     let wrapper = build_dir.join(LIBV5_LIBRARY).join("wrapper.h");
     
     // Add the libv5 path to the list to search for libraries in.
@@ -81,6 +59,9 @@ fn main() -> Result<()>{
         println!("cargo:rustc-link-lib={}", file);
     }
 
+    // Let the crate know which SDK it was built against
+    println!("cargo:rustc-env=VEXRS_SDK_FINGERPRINT={}", sdk.fingerprint);
+
     // We want to rerun this script if wrapper is changed
     println!("cargo:rerun-if-changed={}", wrapper.display());
     
@@ -101,4 +82,4 @@ fn main() -> Result<()>{
         .expect("Unable to write bindings.");
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,173 @@
+// Finds the V5 SDK to build against, keeping every version that has been installed in its own directory
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Context, Result};
+
+/// The environment variable pointing at an SDK to install and build against
+const SDK_PATH_VAR: &str = "LIBV5_PATH";
+
+/// The environment variable choosing an installed SDK by its fingerprint. Projects pin a version by
+/// setting it in the `[env]` table of their `.cargo/config.toml`.
+const SDK_VERSION_VAR: &str = "VEXRS_SDK_VERSION";
+
+/// The files that tell SDK versions apart, relative to the SDK directory
+const FINGERPRINT_FILES: [&str; 2] = ["sdk/vexv5/include/v5.h", "sdk/vexv5/libv5rt.a"];
+
+/// The starting value of the FNV-1a hash
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// The multiplier of the FNV-1a hash
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// An installed version of the SDK
+pub struct Sdk {
+    /// The directory the SDK is installed in
+    pub dir: PathBuf,
+    /// The fingerprint of the SDK, which is also the name of its directory
+    pub fingerprint: String,
+}
+
+impl Sdk {
+    /// Gets the directory libv5rt is in
+    pub fn library_dir(&self) -> PathBuf {
+        self.dir.join("sdk/vexv5")
+    }
+
+    /// Gets the directory v5.h is in
+    pub fn include_dir(&self) -> PathBuf {
+        self.dir.join("sdk/vexv5/include")
+    }
+
+    /// Gets the directory the compiler's headers are in
+    pub fn gcc_include_dir(&self) -> PathBuf {
+        self.dir.join("sdk/vexv5/gcc/include")
+    }
+}
+
+/// Fingerprints the SDK in a directory by hashing its header and library
+fn fingerprint(dir: &Path) -> Result<String> {
+    let mut hash = FNV_OFFSET;
+
+    for file in FINGERPRINT_FILES {
+        let data = fs::read(dir.join(file))
+            .with_context(|| format!("Could not read {} from the SDK at {}", file, dir.display()))?;
+
+        for byte in data {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+/// Gets the directory the data for the V5 is kept in
+fn data_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("Could not find the home directory"))?;
+    Ok(home.join(".v5"))
+}
+
+/// Gets the directory every installed SDK is kept in, each in a directory named after its fingerprint
+fn cache_dir() -> Result<PathBuf> {
+    Ok(data_dir()?.join("sdk"))
+}
+
+/// Copies an SDK into the cache, unless that version is already there
+fn install(source: &Path) -> Result<Sdk> {
+    let fingerprint = fingerprint(source)?;
+    let cache_dir = cache_dir()?;
+    let dir = cache_dir.join(&fingerprint);
+
+    if !dir.exists() {
+        // Copy into a temporary directory first, so that an interrupted copy is never mistaken for an install.
+        // Several builds can install the same SDK at once, so each copies into a directory of its own.
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+        let partial = cache_dir.join(format!("{}.{}-{}.partial", fingerprint, std::process::id(), nanos));
+        fs::create_dir_all(&partial)?;
+
+        let mut co = fs_extra::dir::CopyOptions::new();
+        co.content_only = true;
+        fs_extra::dir::copy(source, &partial, &co)?;
+
+        // If another build finished installing first, its copy is of the same SDK, so keep it
+        if let Err(error) = fs::rename(&partial, &dir) {
+            fs::remove_dir_all(&partial)?;
+            if !dir.exists() {
+                return Err(error).with_context(|| format!("Could not install the SDK into {}", dir.display()));
+            }
+        }
+    }
+
+    Ok(Sdk { dir, fingerprint })
+}
+
+/// Lists the fingerprints of every installed SDK
+fn installed() -> Result<Vec<String>> {
+    let cache_dir = cache_dir()?;
+    if !cache_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(cache_dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.ends_with(".partial") {
+            versions.push(name);
+        }
+    }
+
+    versions.sort();
+    Ok(versions)
+}
+
+/// Finds an installed SDK by its fingerprint, which may be shortened as long as it is unambiguous
+fn find(version: &str) -> Result<Sdk> {
+    let matches: Vec<String> = installed()?.into_iter().filter(|v| v.starts_with(version)).collect();
+
+    match matches.as_slice() {
+        [fingerprint] => Ok(Sdk { dir: cache_dir()?.join(fingerprint), fingerprint: fingerprint.clone() }),
+        [] => Err(anyhow!("SDK {} is not installed. Installed versions: {}", version, installed()?.join(", "))),
+        _ => Err(anyhow!("SDK {} is ambiguous, it could be any of: {}", version, matches.join(", "))),
+    }
+}
+
+/// Chooses the SDK to build against. In order, this is:
+/// 1. The SDK at `LIBV5_PATH`, which is installed first if it is a new version
+/// 2. The installed version named by `VEXRS_SDK_VERSION`
+/// 3. The only installed version
+pub fn select() -> Result<Sdk> {
+    println!("cargo:rerun-if-env-changed={}", SDK_PATH_VAR);
+    println!("cargo:rerun-if-env-changed={}", SDK_VERSION_VAR);
+
+    if let Some(source) = std::env::var_os(SDK_PATH_VAR) {
+        let source = PathBuf::from(source);
+
+        // Notice when the SDK is updated in place, so that it is installed as a new version
+        for file in FINGERPRINT_FILES {
+            println!("cargo:rerun-if-changed={}", source.join(file).display());
+        }
+
+        return install(&source);
+    }
+
+    if let Ok(version) = std::env::var(SDK_VERSION_VAR) {
+        return find(&version);
+    }
+
+    // Earlier versions of this script kept a single SDK in ~/.v5/libv5rt, so bring it into the cache
+    let legacy_dir = data_dir()?.join("libv5rt");
+    if legacy_dir.join(FINGERPRINT_FILES[0]).exists() {
+        install(&legacy_dir)?;
+    }
+
+    match installed()?.as_slice() {
+        [fingerprint] => find(fingerprint),
+        [] => Err(anyhow!("No SDK is installed. Set {} to the SDK to install it", SDK_PATH_VAR)),
+        versions => Err(anyhow!(
+            "Several SDKs are installed, choose one with {}: {}",
+            SDK_VERSION_VAR, versions.join(", "),
+        )),
+    }
+}
@@ -1,8 +1,36 @@
 // Use this so that crates including vexrs-core will automatically use the newlib allocator
+use core::alloc::{GlobalAlloc, Layout};
 use newlib_alloc::Alloc;
+use crate::runtime::CriticalSection;
+
+/// The newlib allocator, run inside a critical section so that the tick can not
+/// switch threads while one of them is halfway through changing the heap
+struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _cs = CriticalSection::enter();
+        Alloc.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _cs = CriticalSection::enter();
+        Alloc.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let _cs = CriticalSection::enter();
+        Alloc.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _cs = CriticalSection::enter();
+        Alloc.realloc(ptr, layout, new_size)
+    }
+}
 
 #[global_allocator]
-static ALLOCATOR: Alloc = Alloc;
+static ALLOCATOR: Allocator = Allocator;
 
 
 #[alloc_error_handler]
@@ -10,4 +38,4 @@ fn alloc_error_handler(_layout: alloc::alloc::Layout) -> ! {
     // Using fmt here increases file size by ~10 Kib !
     panic!("allocation error");
     //panic!("allocation error: {:?}", layout)
-}
\ No newline at end of file
+}
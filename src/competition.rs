@@ -0,0 +1,46 @@
+// Competition mode callbacks for user programs
+
+use crate::runtime::CompetitionMode;
+
+/// The entry points of a competition program. The runtime calls `initialize` once at startup,
+/// then calls the hook for the current mode on the user thread, restarting it every time
+/// the field controller or competition switch changes the mode.
+/// Every hook does nothing by default.
+pub trait Competition: Send + Sync + 'static {
+    /// Runs once on the kernel thread before any other hook
+    fn initialize(&self) {}
+
+    /// Runs while the robot is in autonomous
+    fn autonomous(&self) {}
+
+    /// Runs while the robot is under driver control, or when no competition control is connected
+    fn opcontrol(&self) {}
+
+    /// Runs while the robot is disabled
+    fn disabled(&self) {}
+}
+
+/// Runs a competition program on the global runtime. This must be called from the OS thread and never returns.
+pub fn run<C: Competition>(robot: C) -> ! {
+    robot.initialize();
+
+    crate::RUNTIME.start(
+        move |mode| match mode {
+            CompetitionMode::Disabled => robot.disabled(),
+            CompetitionMode::Autonomous => robot.autonomous(),
+            CompetitionMode::Driver => robot.opcontrol(),
+        },
+        None::<fn()>,
+    )
+}
+
+/// Defines the program's entry point to run a type implementing `Competition`
+#[macro_export]
+macro_rules! competition {
+    ($robot:expr) => {
+        #[no_mangle]
+        extern "C" fn main() {
+            $crate::competition::run($robot)
+        }
+    };
+}
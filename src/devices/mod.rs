@@ -0,0 +1,96 @@
+// Safe wrappers over the devices plugged into the brain
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::hal::{hal, DeviceType, SMART_PORTS};
+
+/// A smart motor
+pub mod motor;
+pub use motor::{BrakeMode, Gearset, Motor, MotorFaults};
+
+/// The smart ports that are claimed by a device, with bit zero for port 1
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+/// The reasons a device can fail
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceError {
+    /// The port number is not one of the brain's smart ports
+    InvalidPort(u8),
+    /// Another device is already using the port
+    PortInUse(u8),
+    /// Nothing is plugged into the port
+    Disconnected,
+    /// A different kind of device is plugged into the port
+    WrongDevice(DeviceType),
+    /// A value was outside of the range the device accepts
+    OutOfRange,
+}
+
+/// A smart port that a device has claimed. No other device can claim the port until it is dropped.
+#[derive(Debug)]
+pub struct SmartPort {
+    /// The port number, starting from 1
+    number: u8,
+}
+
+impl SmartPort {
+    /// Claims a smart port, failing if it does not exist or is already claimed
+    pub fn claim(number: u8) -> Result<SmartPort, DeviceError> {
+        if !(1..=SMART_PORTS).contains(&number) {
+            return Err(DeviceError::InvalidPort(number));
+        }
+
+        let bit = 1 << (number - 1);
+        if CLAIMED.fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return Err(DeviceError::PortInUse(number));
+        }
+
+        Ok(SmartPort { number })
+    }
+
+    /// Gets the port number, starting from 1
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Gets the index libv5rt uses for the port
+    pub fn index(&self) -> u32 {
+        self.number as u32 - 1
+    }
+
+    /// Checks that the expected kind of device is plugged into the port
+    pub fn expect(&self, expected: DeviceType) -> Result<(), DeviceError> {
+        match hal().device_type(self.number).unwrap_or(DeviceType::Empty) {
+            found if found == expected => Ok(()),
+            DeviceType::Empty => Err(DeviceError::Disconnected),
+            found => Err(DeviceError::WrongDevice(found)),
+        }
+    }
+}
+
+impl Drop for SmartPort {
+    fn drop(&mut self) {
+        CLAIMED.fetch_and(!(1 << (self.number - 1)), Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_are_claimed_until_dropped() {
+        let port = SmartPort::claim(5).unwrap();
+        assert_eq!(port.number(), 5);
+        assert_eq!(SmartPort::claim(5).unwrap_err(), DeviceError::PortInUse(5));
+
+        drop(port);
+        assert!(SmartPort::claim(5).is_ok());
+    }
+
+    #[test]
+    fn only_smart_ports_can_be_claimed() {
+        assert_eq!(SmartPort::claim(0).unwrap_err(), DeviceError::InvalidPort(0));
+        assert_eq!(SmartPort::claim(SMART_PORTS + 1).unwrap_err(), DeviceError::InvalidPort(SMART_PORTS + 1));
+    }
+}
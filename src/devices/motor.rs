@@ -0,0 +1,485 @@
+// A safe wrapper over the smart motors, through the hardware abstraction
+
+use crate::hal::{hal, DeviceType};
+use super::{DeviceError, SmartPort};
+
+/// The largest voltage that can be applied to a motor, in millivolts
+pub const MAX_VOLTAGE: i32 = 12000;
+
+/// The largest current limit a motor accepts, in milliamps
+pub const MAX_CURRENT_LIMIT: i32 = 2500;
+
+/// The cartridge fitted to a motor
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gearset {
+    /// The red 36:1 cartridge, with a top speed of 100 rpm
+    Red = 0,
+    /// The green 18:1 cartridge, with a top speed of 200 rpm
+    Green = 1,
+    /// The blue 6:1 cartridge, with a top speed of 600 rpm
+    Blue = 2,
+}
+
+impl Gearset {
+    /// Gets the top speed of the cartridge in rpm
+    pub fn max_rpm(self) -> i32 {
+        match self {
+            Gearset::Red => 100,
+            Gearset::Green => 200,
+            Gearset::Blue => 600,
+        }
+    }
+}
+
+/// What a motor does when it is told to stop
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BrakeMode {
+    /// Let the motor spin down on its own
+    Coast = 0,
+    /// Short the motor to slow it down quickly
+    Brake = 1,
+    /// Actively hold the motor at the position it stopped at
+    Hold = 2,
+}
+
+/// The faults a motor reports
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MotorFaults(u32);
+
+impl MotorFaults {
+    /// The bit set when the motor is too hot
+    pub const OVER_TEMPERATURE: u32 = 1 << 0;
+    /// The bit set when the motor driver has faulted
+    pub const DRIVER_FAULT: u32 = 1 << 1;
+    /// The bit set when the motor is drawing too much current
+    pub const OVER_CURRENT: u32 = 1 << 2;
+    /// The bit set when the motor driver is drawing too much current
+    pub const DRIVER_OVER_CURRENT: u32 = 1 << 3;
+
+    /// Creates the faults from the bits the motor reports
+    pub const fn from_raw(raw: u32) -> MotorFaults {
+        MotorFaults(raw)
+    }
+
+    /// Checks whether any fault is reported
+    pub fn any(self) -> bool {
+        self.0 != 0
+    }
+
+    /// Checks whether the motor is too hot
+    pub fn over_temperature(self) -> bool {
+        self.0 & Self::OVER_TEMPERATURE != 0
+    }
+
+    /// Checks whether the motor driver has faulted
+    pub fn driver_fault(self) -> bool {
+        self.0 & Self::DRIVER_FAULT != 0
+    }
+
+    /// Checks whether the motor is drawing too much current
+    pub fn over_current(self) -> bool {
+        self.0 & Self::OVER_CURRENT != 0
+    }
+
+    /// Checks whether the motor driver is drawing too much current
+    pub fn driver_over_current(self) -> bool {
+        self.0 & Self::DRIVER_OVER_CURRENT != 0
+    }
+}
+
+/// A smart motor plugged into a smart port. Positions are in degrees, velocities in rpm,
+/// voltages in millivolts and currents in milliamps.
+/// Every call checks that the motor is still plugged in, returning `DeviceError::Disconnected` if it is not.
+pub struct Motor {
+    /// The port the motor is plugged into
+    port: SmartPort,
+    /// The cartridge fitted to the motor
+    gearset: Gearset,
+}
+
+impl Motor {
+    /// Claims the motor plugged into a port, setting its cartridge and direction
+    pub fn new(port: u8, gearset: Gearset, reversed: bool) -> Result<Motor, DeviceError> {
+        let motor = Motor { port: SmartPort::claim(port)?, gearset };
+
+        let port = motor.checked_port()?;
+        hal().motor_init(port);
+        hal().motor_set_gearset(port, gearset);
+        hal().motor_set_reversed(port, reversed);
+
+        Ok(motor)
+    }
+
+    /// Gets the port number to pass to the hardware, checking that the motor is still plugged in
+    fn checked_port(&self) -> Result<u8, DeviceError> {
+        self.port.expect(DeviceType::Motor)?;
+
+        Ok(self.port.number())
+    }
+
+    /// Gets the number of the port the motor is plugged into
+    pub fn port(&self) -> u8 {
+        self.port.number()
+    }
+
+    /// Checks that a velocity is within the top speed of the cartridge
+    fn check_velocity(&self, rpm: i32) -> Result<(), DeviceError> {
+        if rpm.abs() > self.gearset.max_rpm() {
+            return Err(DeviceError::OutOfRange);
+        }
+
+        Ok(())
+    }
+
+    /// Spins the motor at a velocity, which must be within the top speed of its cartridge
+    pub fn set_velocity(&mut self, rpm: i32) -> Result<(), DeviceError> {
+        self.check_velocity(rpm)?;
+        let port = self.checked_port()?;
+
+        hal().motor_set_velocity(port, rpm);
+        Ok(())
+    }
+
+    /// Applies a voltage to the motor, between -`MAX_VOLTAGE` and `MAX_VOLTAGE`
+    pub fn set_voltage(&mut self, millivolts: i32) -> Result<(), DeviceError> {
+        if millivolts.abs() > MAX_VOLTAGE {
+            return Err(DeviceError::OutOfRange);
+        }
+        let port = self.checked_port()?;
+
+        hal().motor_set_voltage(port, millivolts);
+        Ok(())
+    }
+
+    /// Moves the motor to a position, at up to `rpm`
+    pub fn move_to(&mut self, degrees: f64, rpm: i32) -> Result<(), DeviceError> {
+        self.check_velocity(rpm)?;
+        let port = self.checked_port()?;
+
+        hal().motor_move_to(port, degrees, rpm);
+        Ok(())
+    }
+
+    /// Moves the motor by an amount relative to the position it is moving to, at up to `rpm`
+    pub fn move_by(&mut self, degrees: f64, rpm: i32) -> Result<(), DeviceError> {
+        self.check_velocity(rpm)?;
+        let port = self.checked_port()?;
+
+        hal().motor_move_by(port, degrees, rpm);
+        Ok(())
+    }
+
+    /// Stops the motor using its brake mode
+    pub fn stop(&mut self) -> Result<(), DeviceError> {
+        self.set_velocity(0)
+    }
+
+    /// Gets the position of the motor
+    pub fn position(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_position(port))
+    }
+
+    /// Sets the current position of the motor, without moving it
+    pub fn set_position(&mut self, degrees: f64) -> Result<(), DeviceError> {
+        let port = self.checked_port()?;
+
+        hal().motor_set_position(port, degrees);
+        Ok(())
+    }
+
+    /// Makes the current position of the motor zero, without moving it
+    pub fn reset_position(&mut self) -> Result<(), DeviceError> {
+        let port = self.checked_port()?;
+
+        hal().motor_reset_position(port);
+        Ok(())
+    }
+
+    /// Gets the velocity the motor is actually spinning at
+    pub fn velocity(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_velocity(port))
+    }
+
+    /// Gets the voltage applied to the motor
+    pub fn voltage(&self) -> Result<i32, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_voltage(port))
+    }
+
+    /// Gets the current the motor is drawing
+    pub fn current(&self) -> Result<i32, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_current(port))
+    }
+
+    /// Limits the current the motor can draw, up to `MAX_CURRENT_LIMIT`
+    pub fn set_current_limit(&mut self, milliamps: i32) -> Result<(), DeviceError> {
+        if !(0..=MAX_CURRENT_LIMIT).contains(&milliamps) {
+            return Err(DeviceError::OutOfRange);
+        }
+        let port = self.checked_port()?;
+
+        hal().motor_set_current_limit(port, milliamps);
+        Ok(())
+    }
+
+    /// Gets the current the motor is limited to
+    pub fn current_limit(&self) -> Result<i32, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_current_limit(port))
+    }
+
+    /// Gets the cartridge fitted to the motor
+    pub fn gearset(&self) -> Result<Gearset, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_gearset(port))
+    }
+
+    /// Sets the cartridge fitted to the motor
+    pub fn set_gearset(&mut self, gearset: Gearset) -> Result<(), DeviceError> {
+        let port = self.checked_port()?;
+
+        hal().motor_set_gearset(port, gearset);
+        self.gearset = gearset;
+        Ok(())
+    }
+
+    /// Gets what the motor does when it is told to stop
+    pub fn brake_mode(&self) -> Result<BrakeMode, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_brake_mode(port))
+    }
+
+    /// Sets what the motor does when it is told to stop
+    pub fn set_brake_mode(&mut self, mode: BrakeMode) -> Result<(), DeviceError> {
+        let port = self.checked_port()?;
+
+        hal().motor_set_brake_mode(port, mode);
+        Ok(())
+    }
+
+    /// Checks whether the motor spins backwards
+    pub fn reversed(&self) -> Result<bool, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_reversed(port))
+    }
+
+    /// Sets whether the motor spins backwards
+    pub fn set_reversed(&mut self, reversed: bool) -> Result<(), DeviceError> {
+        let port = self.checked_port()?;
+
+        hal().motor_set_reversed(port, reversed);
+        Ok(())
+    }
+
+    /// Gets the temperature of the motor in degrees Celsius
+    pub fn temperature(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_temperature(port))
+    }
+
+    /// Gets the efficiency of the motor as a percentage
+    pub fn efficiency(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_efficiency(port))
+    }
+
+    /// Gets the power the motor is drawing in watts
+    pub fn power(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_power(port))
+    }
+
+    /// Gets the torque the motor is producing in newton metres
+    pub fn torque(&self) -> Result<f64, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_torque(port))
+    }
+
+    /// Gets the faults the motor is reporting
+    pub fn faults(&self) -> Result<MotorFaults, DeviceError> {
+        let port = self.checked_port()?;
+
+        Ok(hal().motor_faults(port))
+    }
+}
+
+impl Drop for Motor {
+    /// Stops the motor so that it does not keep running once nothing controls it
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hal::{DeviceType, Mock, MotorCommand, MotorFaults};
+    use crate::runtime::tests::{serial, use_mock};
+    use super::super::DeviceError;
+    use super::{BrakeMode, Gearset, Motor, MAX_CURRENT_LIMIT, MAX_VOLTAGE};
+
+    #[test]
+    fn only_a_plugged_in_motor_can_be_claimed() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        assert_eq!(Motor::new(1, Gearset::Green, false).err(), Some(DeviceError::Disconnected));
+
+        MOCK.plug(1, DeviceType::Rotation);
+        assert_eq!(Motor::new(1, Gearset::Green, false).err(), Some(DeviceError::WrongDevice(DeviceType::Rotation)));
+
+        // Failing gives the port back
+        MOCK.plug(1, DeviceType::Motor);
+        assert!(Motor::new(1, Gearset::Green, false).is_ok());
+    }
+
+    #[test]
+    fn new_motor_is_set_up() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(2, DeviceType::Motor);
+        let motor = Motor::new(2, Gearset::Blue, true).unwrap();
+
+        assert_eq!(motor.port(), 2);
+        let state = MOCK.motor(2);
+        assert!(state.initialized);
+        assert_eq!(state.gearset, Gearset::Blue);
+        assert!(state.reversed);
+    }
+
+    #[test]
+    fn commands_reach_the_motor() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(3, DeviceType::Motor);
+        let mut motor = Motor::new(3, Gearset::Green, false).unwrap();
+
+        motor.set_velocity(-150).unwrap();
+        assert_eq!(MOCK.motor(3).command, Some(MotorCommand::Velocity(-150)));
+
+        motor.set_voltage(MAX_VOLTAGE).unwrap();
+        assert_eq!(MOCK.motor(3).command, Some(MotorCommand::Voltage(MAX_VOLTAGE)));
+
+        // Relative moves are from the position the motor is moving to
+        motor.move_to(90.0, 100).unwrap();
+        motor.move_by(45.0, 50).unwrap();
+        assert_eq!(MOCK.motor(3).command, Some(MotorCommand::Position { degrees: 135.0, rpm: 50 }));
+
+        motor.set_brake_mode(BrakeMode::Hold).unwrap();
+        motor.set_current_limit(1000).unwrap();
+        assert_eq!(motor.brake_mode(), Ok(BrakeMode::Hold));
+        assert_eq!(motor.current_limit(), Ok(1000));
+
+        motor.set_position(30.0).unwrap();
+        assert_eq!(motor.position(), Ok(30.0));
+        motor.reset_position().unwrap();
+        assert_eq!(motor.position(), Ok(0.0));
+    }
+
+    #[test]
+    fn values_out_of_range_are_not_sent() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(4, DeviceType::Motor);
+        let mut motor = Motor::new(4, Gearset::Green, false).unwrap();
+
+        assert_eq!(motor.set_velocity(201), Err(DeviceError::OutOfRange));
+        assert_eq!(motor.move_to(0.0, -201), Err(DeviceError::OutOfRange));
+        assert_eq!(motor.set_voltage(MAX_VOLTAGE + 1), Err(DeviceError::OutOfRange));
+        assert_eq!(motor.set_current_limit(MAX_CURRENT_LIMIT + 1), Err(DeviceError::OutOfRange));
+        assert_eq!(motor.set_current_limit(-1), Err(DeviceError::OutOfRange));
+        assert_eq!(MOCK.motor(4).command, None);
+        assert_eq!(MOCK.motor(4).current_limit, MAX_CURRENT_LIMIT);
+
+        // A faster cartridge allows faster velocities
+        motor.set_gearset(Gearset::Blue).unwrap();
+        motor.set_velocity(600).unwrap();
+        assert_eq!(MOCK.motor(4).command, Some(MotorCommand::Velocity(600)));
+    }
+
+    #[test]
+    fn readings_come_from_the_motor() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(6, DeviceType::Motor);
+        let motor = Motor::new(6, Gearset::Red, false).unwrap();
+
+        MOCK.update_motor(6, |state| {
+            state.velocity = 95.5;
+            state.voltage = 11000;
+            state.current = 1800;
+            state.temperature = 55.0;
+            state.torque = 1.5;
+            state.faults = MotorFaults::from_raw(MotorFaults::OVER_TEMPERATURE | MotorFaults::OVER_CURRENT);
+        });
+
+        assert_eq!(motor.velocity(), Ok(95.5));
+        assert_eq!(motor.voltage(), Ok(11000));
+        assert_eq!(motor.current(), Ok(1800));
+        assert_eq!(motor.temperature(), Ok(55.0));
+        assert_eq!(motor.torque(), Ok(1.5));
+        assert_eq!(motor.gearset(), Ok(Gearset::Red));
+
+        let faults = motor.faults().unwrap();
+        assert!(faults.any());
+        assert!(faults.over_temperature());
+        assert!(faults.over_current());
+        assert!(!faults.driver_fault());
+        assert!(!faults.driver_over_current());
+    }
+
+    #[test]
+    fn unplugged_motor_is_disconnected() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(7, DeviceType::Motor);
+        let mut motor = Motor::new(7, Gearset::Green, false).unwrap();
+
+        MOCK.plug(7, DeviceType::Empty);
+        assert_eq!(motor.position(), Err(DeviceError::Disconnected));
+        assert_eq!(motor.set_velocity(100), Err(DeviceError::Disconnected));
+        assert_eq!(MOCK.motor(7).command, None);
+
+        MOCK.plug(7, DeviceType::Motor);
+        assert!(motor.set_velocity(100).is_ok());
+    }
+
+    #[test]
+    fn dropping_stops_the_motor() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        MOCK.plug(8, DeviceType::Motor);
+        let mut motor = Motor::new(8, Gearset::Green, false).unwrap();
+        motor.set_velocity(100).unwrap();
+
+        drop(motor);
+        assert_eq!(MOCK.motor(8).command, Some(MotorCommand::Velocity(0)));
+    }
+}
@@ -0,0 +1,138 @@
+// The hardware abstraction implemented with std, for running on a host machine
+
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use super::{
+    BrakeMode, Clock, CompetitionMode, CompetitionStatus, DeviceType, Display, Gearset, MotorFaults, SerialPort,
+    SmartMotors, SmartPorts, SMART_PORTS,
+};
+
+/// A host machine, such as a laptop running the test suite. It is always enabled in driver control,
+/// has nothing plugged in and no screen, and uses stdout as its serial link.
+pub struct Host;
+
+impl Host {
+    /// Gets the time the program started, which the clock counts from
+    fn start() -> Instant {
+        static START: OnceLock<Instant> = OnceLock::new();
+        *START.get_or_init(Instant::now)
+    }
+}
+
+impl Clock for Host {
+    fn time_ms(&self) -> u32 {
+        Host::start().elapsed().as_millis() as u32
+    }
+
+    fn time_us(&self) -> u64 {
+        Host::start().elapsed().as_micros() as u64
+    }
+}
+
+impl SerialPort for Host {
+    fn write(&self, data: &[u8]) -> usize {
+        match std::io::stdout().write_all(data) {
+            Ok(()) => data.len(),
+            Err(_) => 0,
+        }
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        None
+    }
+}
+
+impl Display for Host {
+    fn draw_line(&self, _line: i32, _text: &str) {}
+}
+
+impl SmartPorts for Host {
+    fn device_type(&self, port: u8) -> Option<DeviceType> {
+        (1..=SMART_PORTS).contains(&port).then_some(DeviceType::Empty)
+    }
+}
+
+// Nothing is ever plugged in, so no motor can be claimed and these are never reached
+impl SmartMotors for Host {
+    fn motor_init(&self, _port: u8) {}
+
+    fn motor_set_velocity(&self, _port: u8, _rpm: i32) {}
+
+    fn motor_set_voltage(&self, _port: u8, _millivolts: i32) {}
+
+    fn motor_move_to(&self, _port: u8, _degrees: f64, _rpm: i32) {}
+
+    fn motor_move_by(&self, _port: u8, _degrees: f64, _rpm: i32) {}
+
+    fn motor_position(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_set_position(&self, _port: u8, _degrees: f64) {}
+
+    fn motor_reset_position(&self, _port: u8) {}
+
+    fn motor_velocity(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_voltage(&self, _port: u8) -> i32 {
+        0
+    }
+
+    fn motor_current(&self, _port: u8) -> i32 {
+        0
+    }
+
+    fn motor_set_current_limit(&self, _port: u8, _milliamps: i32) {}
+
+    fn motor_current_limit(&self, _port: u8) -> i32 {
+        0
+    }
+
+    fn motor_gearset(&self, _port: u8) -> Gearset {
+        Gearset::Green
+    }
+
+    fn motor_set_gearset(&self, _port: u8, _gearset: Gearset) {}
+
+    fn motor_brake_mode(&self, _port: u8) -> BrakeMode {
+        BrakeMode::Coast
+    }
+
+    fn motor_set_brake_mode(&self, _port: u8, _mode: BrakeMode) {}
+
+    fn motor_reversed(&self, _port: u8) -> bool {
+        false
+    }
+
+    fn motor_set_reversed(&self, _port: u8, _reversed: bool) {}
+
+    fn motor_temperature(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_efficiency(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_power(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_torque(&self, _port: u8) -> f64 {
+        0.0
+    }
+
+    fn motor_faults(&self, _port: u8) -> MotorFaults {
+        MotorFaults::from_raw(0)
+    }
+}
+
+impl CompetitionStatus for Host {
+    fn competition_mode(&self) -> CompetitionMode {
+        CompetitionMode::Driver
+    }
+}
@@ -0,0 +1,391 @@
+// An in-memory hardware abstraction for testing without a brain
+
+use core::cell::{Cell, RefCell};
+use alloc::{collections::{BTreeMap, VecDeque}, string::String, vec::Vec};
+
+use crate::devices::motor::MAX_CURRENT_LIMIT;
+use crate::runtime::CriticalSection;
+use super::{
+    BrakeMode, Clock, CompetitionMode, CompetitionStatus, DeviceType, Display, Gearset, MotorFaults, SerialPort,
+    SmartMotors, SmartPorts, SMART_PORTS,
+};
+
+/// What a mock motor was last told to do
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MotorCommand {
+    /// Spin at a velocity in rpm
+    Velocity(i32),
+    /// Apply a voltage in millivolts
+    Voltage(i32),
+    /// Move to a position in degrees, at up to a velocity in rpm
+    Position {
+        /// The position to move to
+        degrees: f64,
+        /// The fastest the motor may spin on the way
+        rpm: i32,
+    },
+}
+
+/// A pretend smart motor, whose readings are set by the test using it and whose settings can be inspected
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MockMotor {
+    /// Whether the motor has been set up to report positions in degrees
+    pub initialized: bool,
+    /// What the motor was last told to do, or None if it has not been told anything
+    pub command: Option<MotorCommand>,
+    /// The position of the motor in degrees
+    pub position: f64,
+    /// The velocity the motor is actually spinning at in rpm
+    pub velocity: f64,
+    /// The voltage applied to the motor in millivolts
+    pub voltage: i32,
+    /// The current the motor is drawing in milliamps
+    pub current: i32,
+    /// The current the motor is limited to in milliamps
+    pub current_limit: i32,
+    /// The cartridge fitted to the motor
+    pub gearset: Gearset,
+    /// What the motor does when it is told to stop
+    pub brake_mode: BrakeMode,
+    /// Whether the motor spins backwards
+    pub reversed: bool,
+    /// The temperature of the motor in degrees Celsius
+    pub temperature: f64,
+    /// The efficiency of the motor as a percentage
+    pub efficiency: f64,
+    /// The power the motor is drawing in watts
+    pub power: f64,
+    /// The torque the motor is producing in newton metres
+    pub torque: f64,
+    /// The faults the motor is reporting
+    pub faults: MotorFaults,
+}
+
+impl MockMotor {
+    /// Creates a motor that has never been used, standing still with a green cartridge
+    pub const fn new() -> MockMotor {
+        MockMotor {
+            initialized: false,
+            command: None,
+            position: 0.0,
+            velocity: 0.0,
+            voltage: 0,
+            current: 0,
+            current_limit: MAX_CURRENT_LIMIT,
+            gearset: Gearset::Green,
+            brake_mode: BrakeMode::Coast,
+            reversed: false,
+            temperature: 0.0,
+            efficiency: 0.0,
+            power: 0.0,
+            torque: 0.0,
+            faults: MotorFaults::from_raw(0),
+        }
+    }
+}
+
+impl Default for MockMotor {
+    fn default() -> Self {
+        MockMotor::new()
+    }
+}
+
+/// Pretend hardware whose time, serial input, devices and competition mode are set by the test using it,
+/// and whose serial output and screen can be inspected
+pub struct Mock {
+    /// The current time in microseconds
+    time_us: Cell<u64>,
+    /// Bytes waiting to be read from the serial link
+    serial_in: RefCell<VecDeque<u8>>,
+    /// Bytes written to the serial link
+    serial_out: RefCell<Vec<u8>>,
+    /// The text drawn on each line of the screen
+    lines: RefCell<BTreeMap<i32, String>>,
+    /// The device plugged into each smart port
+    ports: RefCell<[DeviceType; SMART_PORTS as usize]>,
+    /// The motor on each smart port, used while a motor is plugged in
+    motors: RefCell<[MockMotor; SMART_PORTS as usize]>,
+    /// The current competition mode
+    mode: Cell<CompetitionMode>,
+}
+
+// Only one thread runs at a time, and every access to the cells is made inside a critical section,
+// so the tick can not switch to another thread using the mock while one is partway through
+unsafe impl Sync for Mock {}
+
+impl Mock {
+    /// Creates a mock at time zero in driver control, with nothing plugged in
+    pub const fn new() -> Mock {
+        Mock {
+            time_us: Cell::new(0),
+            serial_in: RefCell::new(VecDeque::new()),
+            serial_out: RefCell::new(Vec::new()),
+            lines: RefCell::new(BTreeMap::new()),
+            ports: RefCell::new([DeviceType::Empty; SMART_PORTS as usize]),
+            motors: RefCell::new([MockMotor::new(); SMART_PORTS as usize]),
+            mode: Cell::new(CompetitionMode::Driver),
+        }
+    }
+
+    /// Sets the current time in microseconds
+    pub fn set_time_us(&self, time: u64) {
+        let _cs = CriticalSection::enter();
+        self.time_us.set(time);
+    }
+
+    /// Moves the time forward by a number of milliseconds
+    pub fn advance_ms(&self, ms: u32) {
+        let _cs = CriticalSection::enter();
+        self.time_us.set(self.time_us.get() + ms as u64 * 1000);
+    }
+
+    /// Queues bytes to be read from the serial link
+    pub fn push_serial_input(&self, data: &[u8]) {
+        let _cs = CriticalSection::enter();
+        self.serial_in.borrow_mut().extend(data);
+    }
+
+    /// Takes everything written to the serial link so far
+    pub fn take_serial_output(&self) -> Vec<u8> {
+        let _cs = CriticalSection::enter();
+        core::mem::take(&mut *self.serial_out.borrow_mut())
+    }
+
+    /// Gets the text last drawn on a line of the screen
+    pub fn line(&self, line: i32) -> Option<String> {
+        let _cs = CriticalSection::enter();
+        self.lines.borrow().get(&line).cloned()
+    }
+
+    /// Plugs a device into a smart port, or unplugs it with `DeviceType::Empty`
+    /// # Panics
+    /// Panics if there is no such port
+    pub fn plug(&self, port: u8, device: DeviceType) {
+        assert!((1..=SMART_PORTS).contains(&port), "There is no smart port {}", port);
+        let _cs = CriticalSection::enter();
+        self.ports.borrow_mut()[port as usize - 1] = device;
+    }
+
+    /// Gets the state of the motor on a smart port
+    /// # Panics
+    /// Panics if there is no such port
+    pub fn motor(&self, port: u8) -> MockMotor {
+        self.update_motor(port, |motor| *motor)
+    }
+
+    /// Changes the state of the motor on a smart port, such as what it reports.
+    /// The change runs inside a critical section, so it must not block or yield.
+    /// # Panics
+    /// Panics if there is no such port
+    pub fn update_motor<R>(&self, port: u8, f: impl FnOnce(&mut MockMotor) -> R) -> R {
+        assert!((1..=SMART_PORTS).contains(&port), "There is no smart port {}", port);
+        let _cs = CriticalSection::enter();
+        f(&mut self.motors.borrow_mut()[port as usize - 1])
+    }
+
+    /// Sets the competition mode
+    pub fn set_competition_mode(&self, mode: CompetitionMode) {
+        let _cs = CriticalSection::enter();
+        self.mode.set(mode);
+    }
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Mock::new()
+    }
+}
+
+impl Clock for Mock {
+    fn time_ms(&self) -> u32 {
+        let _cs = CriticalSection::enter();
+        (self.time_us.get() / 1000) as u32
+    }
+
+    fn time_us(&self) -> u64 {
+        let _cs = CriticalSection::enter();
+        self.time_us.get()
+    }
+}
+
+impl SerialPort for Mock {
+    fn write(&self, data: &[u8]) -> usize {
+        let _cs = CriticalSection::enter();
+        self.serial_out.borrow_mut().extend_from_slice(data);
+        data.len()
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        let _cs = CriticalSection::enter();
+        self.serial_in.borrow_mut().pop_front()
+    }
+}
+
+impl Display for Mock {
+    fn draw_line(&self, line: i32, text: &str) {
+        let _cs = CriticalSection::enter();
+        self.lines.borrow_mut().insert(line, String::from(text));
+    }
+}
+
+impl SmartPorts for Mock {
+    fn device_type(&self, port: u8) -> Option<DeviceType> {
+        let _cs = CriticalSection::enter();
+        if !(1..=SMART_PORTS).contains(&port) {
+            return None;
+        }
+
+        Some(self.ports.borrow()[port as usize - 1])
+    }
+}
+
+impl SmartMotors for Mock {
+    fn motor_init(&self, port: u8) {
+        self.update_motor(port, |motor| motor.initialized = true);
+    }
+
+    fn motor_set_velocity(&self, port: u8, rpm: i32) {
+        self.update_motor(port, |motor| motor.command = Some(MotorCommand::Velocity(rpm)));
+    }
+
+    fn motor_set_voltage(&self, port: u8, millivolts: i32) {
+        self.update_motor(port, |motor| motor.command = Some(MotorCommand::Voltage(millivolts)));
+    }
+
+    fn motor_move_to(&self, port: u8, degrees: f64, rpm: i32) {
+        self.update_motor(port, |motor| motor.command = Some(MotorCommand::Position { degrees, rpm }));
+    }
+
+    fn motor_move_by(&self, port: u8, degrees: f64, rpm: i32) {
+        self.update_motor(port, |motor| {
+            let from = match motor.command {
+                Some(MotorCommand::Position { degrees, .. }) => degrees,
+                _ => motor.position,
+            };
+            motor.command = Some(MotorCommand::Position { degrees: from + degrees, rpm });
+        });
+    }
+
+    fn motor_position(&self, port: u8) -> f64 {
+        self.motor(port).position
+    }
+
+    fn motor_set_position(&self, port: u8, degrees: f64) {
+        self.update_motor(port, |motor| motor.position = degrees);
+    }
+
+    fn motor_reset_position(&self, port: u8) {
+        self.update_motor(port, |motor| motor.position = 0.0);
+    }
+
+    fn motor_velocity(&self, port: u8) -> f64 {
+        self.motor(port).velocity
+    }
+
+    fn motor_voltage(&self, port: u8) -> i32 {
+        self.motor(port).voltage
+    }
+
+    fn motor_current(&self, port: u8) -> i32 {
+        self.motor(port).current
+    }
+
+    fn motor_set_current_limit(&self, port: u8, milliamps: i32) {
+        self.update_motor(port, |motor| motor.current_limit = milliamps);
+    }
+
+    fn motor_current_limit(&self, port: u8) -> i32 {
+        self.motor(port).current_limit
+    }
+
+    fn motor_gearset(&self, port: u8) -> Gearset {
+        self.motor(port).gearset
+    }
+
+    fn motor_set_gearset(&self, port: u8, gearset: Gearset) {
+        self.update_motor(port, |motor| motor.gearset = gearset);
+    }
+
+    fn motor_brake_mode(&self, port: u8) -> BrakeMode {
+        self.motor(port).brake_mode
+    }
+
+    fn motor_set_brake_mode(&self, port: u8, mode: BrakeMode) {
+        self.update_motor(port, |motor| motor.brake_mode = mode);
+    }
+
+    fn motor_reversed(&self, port: u8) -> bool {
+        self.motor(port).reversed
+    }
+
+    fn motor_set_reversed(&self, port: u8, reversed: bool) {
+        self.update_motor(port, |motor| motor.reversed = reversed);
+    }
+
+    fn motor_temperature(&self, port: u8) -> f64 {
+        self.motor(port).temperature
+    }
+
+    fn motor_efficiency(&self, port: u8) -> f64 {
+        self.motor(port).efficiency
+    }
+
+    fn motor_power(&self, port: u8) -> f64 {
+        self.motor(port).power
+    }
+
+    fn motor_torque(&self, port: u8) -> f64 {
+        self.motor(port).torque
+    }
+
+    fn motor_faults(&self, port: u8) -> MotorFaults {
+        self.motor(port).faults
+    }
+}
+
+impl CompetitionStatus for Mock {
+    fn competition_mode(&self) -> CompetitionMode {
+        let _cs = CriticalSection::enter();
+        self.mode.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serial_is_scripted_and_recorded() {
+        let mock = Mock::new();
+
+        mock.push_serial_input(b"hi");
+        assert_eq!(mock.read_byte(), Some(b'h'));
+        assert_eq!(mock.read_byte(), Some(b'i'));
+        assert_eq!(mock.read_byte(), None);
+
+        assert_eq!(mock.write(b"hello"), 5);
+        assert_eq!(mock.take_serial_output(), b"hello");
+        assert!(mock.take_serial_output().is_empty());
+    }
+
+    #[test]
+    fn devices_are_plugged_into_ports() {
+        let mock = Mock::new();
+
+        mock.plug(3, DeviceType::Motor);
+
+        assert_eq!(mock.device_type(3), Some(DeviceType::Motor));
+        assert_eq!(mock.device_type(4), Some(DeviceType::Empty));
+        assert_eq!(mock.device_type(0), None);
+        assert_eq!(mock.device_type(SMART_PORTS + 1), None);
+    }
+
+    #[test]
+    fn clock_advances() {
+        let mock = Mock::new();
+
+        mock.advance_ms(5);
+        assert_eq!(mock.time_ms(), 5);
+        assert_eq!(mock.time_us(), 5000);
+    }
+}
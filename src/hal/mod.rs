@@ -0,0 +1,223 @@
+// Traits over the hardware the runtime uses, so that it does not depend on libv5rt directly
+
+use core::cell::UnsafeCell;
+
+pub use crate::runtime::CompetitionMode;
+pub use crate::devices::{BrakeMode, Gearset, MotorFaults};
+
+/// The implementation backed by libv5rt
+#[cfg(all(feature = "sdk", target_arch = "arm"))]
+pub mod v5;
+#[cfg(all(feature = "sdk", target_arch = "arm"))]
+pub use v5::V5;
+
+/// The implementation used on a host machine
+#[cfg(not(target_arch = "arm"))]
+pub mod host;
+#[cfg(not(target_arch = "arm"))]
+pub use host::Host;
+
+/// An in-memory implementation that can be scripted and inspected
+pub mod mock;
+pub use mock::{Mock, MockMotor, MotorCommand};
+
+/// The number of smart ports on the brain. Ports are numbered from 1.
+pub const SMART_PORTS: u8 = 21;
+
+/// Reads the system time
+pub trait Clock {
+    /// Gets the time since the program started in milliseconds
+    fn time_ms(&self) -> u32;
+
+    /// Gets the time since the program started in microseconds
+    fn time_us(&self) -> u64;
+}
+
+/// Sends and receives data over the serial link
+pub trait SerialPort {
+    /// Writes data to the serial link, returning how many bytes were written
+    fn write(&self, data: &[u8]) -> usize;
+
+    /// Reads a byte from the serial link, if one has been received
+    fn read_byte(&self) -> Option<u8>;
+}
+
+/// Draws on the brain's screen
+pub trait Display {
+    /// Draws a line of text on the screen
+    fn draw_line(&self, line: i32, text: &str);
+}
+
+/// The kind of device plugged into a smart port
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeviceType {
+    /// Nothing is plugged in
+    Empty,
+    /// A smart motor
+    Motor,
+    /// A rotation sensor
+    Rotation,
+    /// An inertial sensor
+    Imu,
+    /// A distance sensor
+    Distance,
+    /// A radio
+    Radio,
+    /// A vision sensor
+    Vision,
+    /// A three wire expander
+    Adi,
+    /// An optical sensor
+    Optical,
+    /// A GPS sensor
+    Gps,
+    /// A device the runtime does not know about, with its raw libv5rt type
+    Other(u32),
+}
+
+impl DeviceType {
+    /// Converts a libv5rt device type
+    pub fn from_raw(raw: u32) -> DeviceType {
+        match raw {
+            0 => DeviceType::Empty,
+            2 => DeviceType::Motor,
+            4 => DeviceType::Rotation,
+            6 => DeviceType::Imu,
+            7 => DeviceType::Distance,
+            8 => DeviceType::Radio,
+            11 => DeviceType::Vision,
+            12 => DeviceType::Adi,
+            16 => DeviceType::Optical,
+            20 => DeviceType::Gps,
+            other => DeviceType::Other(other),
+        }
+    }
+}
+
+/// Finds out what is plugged into the smart ports
+pub trait SmartPorts {
+    /// Gets the type of device plugged into a port, or None if there is no such port
+    fn device_type(&self, port: u8) -> Option<DeviceType>;
+}
+
+/// Drives the smart motors plugged into the smart ports. Positions are in degrees, velocities in rpm,
+/// voltages in millivolts and currents in milliamps. Ports are numbered from 1 and are checked by the caller.
+pub trait SmartMotors {
+    /// Sets up the motor plugged into a port, so that it reports positions in degrees
+    fn motor_init(&self, port: u8);
+
+    /// Spins a motor at a velocity
+    fn motor_set_velocity(&self, port: u8, rpm: i32);
+
+    /// Applies a voltage to a motor
+    fn motor_set_voltage(&self, port: u8, millivolts: i32);
+
+    /// Moves a motor to a position, at up to `rpm`
+    fn motor_move_to(&self, port: u8, degrees: f64, rpm: i32);
+
+    /// Moves a motor by an amount relative to the position it is moving to, at up to `rpm`
+    fn motor_move_by(&self, port: u8, degrees: f64, rpm: i32);
+
+    /// Gets the position of a motor
+    fn motor_position(&self, port: u8) -> f64;
+
+    /// Sets the current position of a motor, without moving it
+    fn motor_set_position(&self, port: u8, degrees: f64);
+
+    /// Makes the current position of a motor zero, without moving it
+    fn motor_reset_position(&self, port: u8);
+
+    /// Gets the velocity a motor is actually spinning at
+    fn motor_velocity(&self, port: u8) -> f64;
+
+    /// Gets the voltage applied to a motor
+    fn motor_voltage(&self, port: u8) -> i32;
+
+    /// Gets the current a motor is drawing
+    fn motor_current(&self, port: u8) -> i32;
+
+    /// Limits the current a motor can draw
+    fn motor_set_current_limit(&self, port: u8, milliamps: i32);
+
+    /// Gets the current a motor is limited to
+    fn motor_current_limit(&self, port: u8) -> i32;
+
+    /// Gets the cartridge fitted to a motor
+    fn motor_gearset(&self, port: u8) -> Gearset;
+
+    /// Sets the cartridge fitted to a motor
+    fn motor_set_gearset(&self, port: u8, gearset: Gearset);
+
+    /// Gets what a motor does when it is told to stop
+    fn motor_brake_mode(&self, port: u8) -> BrakeMode;
+
+    /// Sets what a motor does when it is told to stop
+    fn motor_set_brake_mode(&self, port: u8, mode: BrakeMode);
+
+    /// Checks whether a motor spins backwards
+    fn motor_reversed(&self, port: u8) -> bool;
+
+    /// Sets whether a motor spins backwards
+    fn motor_set_reversed(&self, port: u8, reversed: bool);
+
+    /// Gets the temperature of a motor in degrees Celsius
+    fn motor_temperature(&self, port: u8) -> f64;
+
+    /// Gets the efficiency of a motor as a percentage
+    fn motor_efficiency(&self, port: u8) -> f64;
+
+    /// Gets the power a motor is drawing in watts
+    fn motor_power(&self, port: u8) -> f64;
+
+    /// Gets the torque a motor is producing in newton metres
+    fn motor_torque(&self, port: u8) -> f64;
+
+    /// Gets the faults a motor is reporting
+    fn motor_faults(&self, port: u8) -> MotorFaults;
+}
+
+/// Reads the competition status from the field controller or competition switch
+pub trait CompetitionStatus {
+    /// Gets the mode the robot is in
+    fn competition_mode(&self) -> CompetitionMode;
+}
+
+/// Everything the runtime needs from the hardware
+pub trait Hal: Clock + SerialPort + Display + SmartPorts + SmartMotors + CompetitionStatus + Sync {}
+
+impl<T: Clock + SerialPort + Display + SmartPorts + SmartMotors + CompetitionStatus + Sync> Hal for T {}
+
+/// Holds the implementation everything is using
+struct Current(UnsafeCell<&'static dyn Hal>);
+
+// The implementation is only changed by `set_hal`, whose caller makes sure nothing is using it
+unsafe impl Sync for Current {}
+
+/// The implementation used until it is replaced, which is libv5rt on the brain
+#[cfg(all(feature = "sdk", target_arch = "arm"))]
+static DEFAULT: V5 = V5;
+
+/// The implementation used until it is replaced, which is std on a host machine
+#[cfg(not(target_arch = "arm"))]
+static DEFAULT: Host = Host;
+
+/// The implementation used until it is replaced. Without the SDK there is no way to
+/// reach the hardware, so this is a mock until the program installs its own.
+#[cfg(all(not(feature = "sdk"), target_arch = "arm"))]
+static DEFAULT: Mock = Mock::new();
+
+/// The implementation everything is using
+static CURRENT: Current = Current(UnsafeCell::new(&DEFAULT));
+
+/// Gets the implementation everything is using
+pub fn hal() -> &'static dyn Hal {
+    unsafe { *CURRENT.0.get() }
+}
+
+/// Replaces the implementation everything uses, returning the old one
+/// # Safety
+/// Nothing may be using the implementation while it is replaced, so this should be done
+/// before the runtime is started or while only one thread can run.
+pub unsafe fn set_hal(hal: &'static dyn Hal) -> &'static dyn Hal {
+    core::mem::replace(&mut *CURRENT.0.get(), hal)
+}
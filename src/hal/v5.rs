@@ -0,0 +1,261 @@
+// The hardware abstraction implemented with libv5rt
+
+use core::cell::UnsafeCell;
+use alloc::vec::Vec;
+
+use crate::libv5rt;
+use crate::runtime::CriticalSection;
+use super::{
+    BrakeMode, Clock, CompetitionMode, CompetitionStatus, DeviceType, Display, Gearset, MotorFaults, SerialPort,
+    SmartMotors, SmartPorts, SMART_PORTS,
+};
+
+/// The serial channel connected to the USB port
+const SERIAL_CHANNEL: u32 = 1;
+
+/// The number of ports libv5rt reports the status of, including the brain's internal ones
+const DEVICE_PORTS: usize = 32;
+
+/// The libv5rt encoder units for degrees, which every motor is set to
+const ENCODER_DEGREES: u32 = 0;
+
+/// The competition status bit that is set while the robot is disabled
+const STATUS_DISABLED: u32 = 1 << 0;
+
+/// The competition status bit that is set while the robot is in autonomous
+const STATUS_AUTONOMOUS: u32 = 1 << 1;
+
+/// The V5 brain, through libv5rt
+pub struct V5;
+
+/// The types of the devices plugged into the smart ports, as last read from libv5rt
+struct Devices {
+    /// The time in milliseconds the types were read at, or None if they have never been read
+    read_at: Option<u32>,
+    /// The type of the device plugged into each smart port
+    types: [DeviceType; SMART_PORTS as usize],
+}
+
+/// Holds the device types, only accessed inside critical sections
+struct DeviceCache(UnsafeCell<Devices>);
+
+// The device types are only touched inside critical sections
+unsafe impl Sync for DeviceCache {}
+
+/// libv5rt reads the status of every port at once, so the types are kept for the rest of the millisecond
+/// instead of being read again each time a device checks it is still plugged in
+static DEVICES: DeviceCache = DeviceCache(UnsafeCell::new(Devices {
+    read_at: None,
+    types: [DeviceType::Empty; SMART_PORTS as usize],
+}));
+
+impl V5 {
+    /// Gets the libv5rt device plugged into a smart port
+    fn device(port: u8) -> libv5rt::V5_DeviceT {
+        unsafe { libv5rt::vexDeviceGetByIndex(port as u32 - 1) }
+    }
+}
+
+impl Gearset {
+    /// Converts a libv5rt gearset
+    fn from_raw(raw: u32) -> Gearset {
+        match raw {
+            0 => Gearset::Red,
+            2 => Gearset::Blue,
+            _ => Gearset::Green,
+        }
+    }
+}
+
+impl BrakeMode {
+    /// Converts a libv5rt brake mode
+    fn from_raw(raw: u32) -> BrakeMode {
+        match raw {
+            1 => BrakeMode::Brake,
+            2 => BrakeMode::Hold,
+            _ => BrakeMode::Coast,
+        }
+    }
+}
+
+impl Clock for V5 {
+    fn time_ms(&self) -> u32 {
+        unsafe { libv5rt::vexSystemTimeGet() }
+    }
+
+    fn time_us(&self) -> u64 {
+        unsafe { libv5rt::vexSystemHighResTimeGet() }
+    }
+}
+
+impl SerialPort for V5 {
+    fn write(&self, data: &[u8]) -> usize {
+        // libv5rt does not write to the buffer, it only takes it as mutable
+        let written = unsafe { libv5rt::vexSerialWriteBuffer(SERIAL_CHANNEL, data.as_ptr() as *mut u8, data.len() as u32) };
+
+        written.max(0) as usize
+    }
+
+    fn read_byte(&self) -> Option<u8> {
+        let data = unsafe { libv5rt::vexSerialReadChar(SERIAL_CHANNEL) };
+
+        // Anything out of range means there is nothing to read
+        u8::try_from(data).ok()
+    }
+}
+
+impl Display for V5 {
+    fn draw_line(&self, line: i32, text: &str) {
+        // The text is used as a format string, so escape any format specifiers and null terminate it
+        let mut format = Vec::with_capacity(text.len() + 1);
+        for byte in text.bytes() {
+            if byte == b'%' {
+                format.push(b'%');
+            }
+            format.push(byte);
+        }
+        format.push(0);
+
+        unsafe {
+            libv5rt::vexDisplayString(line, format.as_ptr() as *const _);
+        }
+    }
+}
+
+impl SmartPorts for V5 {
+    fn device_type(&self, port: u8) -> Option<DeviceType> {
+        if !(1..=SMART_PORTS).contains(&port) {
+            return None;
+        }
+
+        let _cs = CriticalSection::enter();
+        let devices = unsafe { &mut *DEVICES.0.get() };
+
+        let now = self.time_ms();
+        if devices.read_at != Some(now) {
+            let mut status: [libv5rt::V5_DeviceType; DEVICE_PORTS] = unsafe { core::mem::zeroed() };
+            unsafe {
+                libv5rt::vexDeviceGetStatus(status.as_mut_ptr());
+            }
+
+            for (index, device) in devices.types.iter_mut().enumerate() {
+                *device = DeviceType::from_raw(status[index] as u32);
+            }
+            devices.read_at = Some(now);
+        }
+
+        Some(devices.types[port as usize - 1])
+    }
+}
+
+impl SmartMotors for V5 {
+    fn motor_init(&self, port: u8) {
+        unsafe { libv5rt::vexDeviceMotorEncoderUnitsSet(V5::device(port), ENCODER_DEGREES as _) };
+    }
+
+    fn motor_set_velocity(&self, port: u8, rpm: i32) {
+        unsafe { libv5rt::vexDeviceMotorVelocitySet(V5::device(port), rpm) };
+    }
+
+    fn motor_set_voltage(&self, port: u8, millivolts: i32) {
+        unsafe { libv5rt::vexDeviceMotorVoltageSet(V5::device(port), millivolts) };
+    }
+
+    fn motor_move_to(&self, port: u8, degrees: f64, rpm: i32) {
+        unsafe { libv5rt::vexDeviceMotorAbsoluteTargetSet(V5::device(port), degrees, rpm) };
+    }
+
+    fn motor_move_by(&self, port: u8, degrees: f64, rpm: i32) {
+        unsafe { libv5rt::vexDeviceMotorRelativeTargetSet(V5::device(port), degrees, rpm) };
+    }
+
+    fn motor_position(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorPositionGet(V5::device(port)) }
+    }
+
+    fn motor_set_position(&self, port: u8, degrees: f64) {
+        unsafe { libv5rt::vexDeviceMotorPositionSet(V5::device(port), degrees) };
+    }
+
+    fn motor_reset_position(&self, port: u8) {
+        unsafe { libv5rt::vexDeviceMotorPositionReset(V5::device(port)) };
+    }
+
+    fn motor_velocity(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorActualVelocityGet(V5::device(port)) }
+    }
+
+    fn motor_voltage(&self, port: u8) -> i32 {
+        unsafe { libv5rt::vexDeviceMotorVoltageGet(V5::device(port)) }
+    }
+
+    fn motor_current(&self, port: u8) -> i32 {
+        unsafe { libv5rt::vexDeviceMotorCurrentGet(V5::device(port)) }
+    }
+
+    fn motor_set_current_limit(&self, port: u8, milliamps: i32) {
+        unsafe { libv5rt::vexDeviceMotorCurrentLimitSet(V5::device(port), milliamps) };
+    }
+
+    fn motor_current_limit(&self, port: u8) -> i32 {
+        unsafe { libv5rt::vexDeviceMotorCurrentLimitGet(V5::device(port)) }
+    }
+
+    fn motor_gearset(&self, port: u8) -> Gearset {
+        Gearset::from_raw(unsafe { libv5rt::vexDeviceMotorGearingGet(V5::device(port)) } as u32)
+    }
+
+    fn motor_set_gearset(&self, port: u8, gearset: Gearset) {
+        unsafe { libv5rt::vexDeviceMotorGearingSet(V5::device(port), gearset as _) };
+    }
+
+    fn motor_brake_mode(&self, port: u8) -> BrakeMode {
+        BrakeMode::from_raw(unsafe { libv5rt::vexDeviceMotorBrakeModeGet(V5::device(port)) } as u32)
+    }
+
+    fn motor_set_brake_mode(&self, port: u8, mode: BrakeMode) {
+        unsafe { libv5rt::vexDeviceMotorBrakeModeSet(V5::device(port), mode as _) };
+    }
+
+    fn motor_reversed(&self, port: u8) -> bool {
+        unsafe { libv5rt::vexDeviceMotorReverseFlagGet(V5::device(port)) }
+    }
+
+    fn motor_set_reversed(&self, port: u8, reversed: bool) {
+        unsafe { libv5rt::vexDeviceMotorReverseFlagSet(V5::device(port), reversed) };
+    }
+
+    fn motor_temperature(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorTemperatureGet(V5::device(port)) }
+    }
+
+    fn motor_efficiency(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorEfficiencyGet(V5::device(port)) }
+    }
+
+    fn motor_power(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorPowerGet(V5::device(port)) }
+    }
+
+    fn motor_torque(&self, port: u8) -> f64 {
+        unsafe { libv5rt::vexDeviceMotorTorqueGet(V5::device(port)) }
+    }
+
+    fn motor_faults(&self, port: u8) -> MotorFaults {
+        MotorFaults::from_raw(unsafe { libv5rt::vexDeviceMotorFaultsGet(V5::device(port)) })
+    }
+}
+
+impl CompetitionStatus for V5 {
+    fn competition_mode(&self) -> CompetitionMode {
+        let status = unsafe { libv5rt::vexCompetitionStatus() };
+
+        if status & STATUS_DISABLED != 0 {
+            CompetitionMode::Disabled
+        } else if status & STATUS_AUTONOMOUS != 0 {
+            CompetitionMode::Autonomous
+        } else {
+            CompetitionMode::Driver
+        }
+    }
+}
@@ -1,24 +1,43 @@
 #![no_std]
-#![feature(alloc_error_handler)]
-#![feature(core_intrinsics)]
+#![cfg_attr(target_arch = "arm", feature(alloc_error_handler))]
 
 extern crate alloc;
 
+// Host builds, such as the test suite, use std for the panic handler, allocator and hardware abstraction
+#[cfg(not(target_arch = "arm"))]
+extern crate std;
+
+#[cfg(all(feature = "preemptive", not(target_arch = "arm")))]
+compile_error!("The preemptive feature is only supported on the V5");
+
 /// A panic handler implementation
+#[cfg(target_arch = "arm")]
 mod panic;
 
 /// Registers the newlib allocator as the default rust allocator
+#[cfg(target_arch = "arm")]
 mod allocator;
 
 /// The automatically generated libv5rt bindings
+#[cfg(all(feature = "sdk", target_arch = "arm"))]
 pub mod libv5rt;
 
+/// Traits over the hardware, implemented with libv5rt, std on a host, or mocked for testing
+pub mod hal;
+
 /// The core Vexrs runtime.
 pub mod runtime;
 pub use runtime::RUNTIME;
 
+/// Safe wrappers over the devices plugged into the brain
+pub mod devices;
+
 /// Synchronization primitives that build on top of the runtime
 pub mod sync;
 
+/// Competition mode callbacks
+pub mod competition;
+pub use competition::Competition;
+
 /// A serial writer implementation
 pub mod serial;
\ No newline at end of file
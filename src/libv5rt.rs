@@ -5,4 +5,7 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
\ No newline at end of file
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+/// The fingerprint of the SDK these bindings were generated from
+pub const SDK_FINGERPRINT: &str = env!("VEXRS_SDK_FINGERPRINT");
@@ -1,14 +1,13 @@
 #![no_std]
 #![no_main]
 
-use vexrs::libv5rt;
-
 extern crate vexrs;
 extern crate alloc;
 
 use vexrs::println;
 
 use vexrs::RUNTIME;
+use vexrs::Competition;
 use vexrs::sync::mutex::Mutex;
 
 lazy_static::lazy_static! {
@@ -17,32 +16,33 @@ lazy_static::lazy_static! {
 
 fn task() {
     loop {
-        unsafe {
+        {
             let _mtx = GMUTEX.acquire();
             println!("Hello, Task!");
-            let t = libv5rt::vexSystemTimeGet();
-            while libv5rt::vexSystemTimeGet() - t < 1000 {
-                RUNTIME.yield_next();
-            }
+            RUNTIME.sleep_ms(1000);
         }
         RUNTIME.yield_next();
         
     }
 }
 
-#[no_mangle]
-extern "C" fn main() {
-    RUNTIME.spawn(task);
-    loop {
-        unsafe {
-            let _mtx = GMUTEX.acquire();
-            println!("Hello, Main!");
-            let t = libv5rt::vexSystemTimeGet();
-            while libv5rt::vexSystemTimeGet() - t < 5000 {
-                RUNTIME.yield_next();
+struct Robot;
+
+impl Competition for Robot {
+    fn initialize(&self) {
+        RUNTIME.spawn(task).expect("Failed to spawn task");
+    }
+
+    fn opcontrol(&self) {
+        loop {
+            {
+                let _mtx = GMUTEX.acquire();
+                println!("Hello, Main!");
+                RUNTIME.sleep_ms(5000);
             }
-            
+            RUNTIME.yield_next();
         }
-        RUNTIME.yield_next();
     }
 }
+
+vexrs::competition!(Robot);
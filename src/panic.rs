@@ -4,15 +4,14 @@ use core::panic::PanicInfo;
 
 use alloc::string::ToString;
 
+use crate::hal::hal;
+
 /// Called on panic. Just loops for now.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     crate::println!("{}", info.to_string());
     
     loop {
-        unsafe {
-            crate::libv5rt::vexDisplayString(1, info.to_string().as_ptr());
-            
-        }
+        hal().draw_line(1, &info.to_string());
     }
 }
\ No newline at end of file
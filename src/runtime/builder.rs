@@ -0,0 +1,56 @@
+// A builder for configuring threads before they are spawned
+
+use alloc::string::String;
+
+use super::{JoinHandle, SpawnError};
+use super::thread::{DEFAULT_PRIORITY, DEFAULT_STACK_SIZE};
+
+/// Configures a thread's stack size, priority and name before spawning it
+pub struct ThreadBuilder {
+    /// The size of the thread's stack in bytes
+    pub(crate) stack_size: usize,
+    /// The scheduling priority of the thread
+    pub(crate) priority: u8,
+    /// The name of the thread
+    pub(crate) name: Option<String>,
+}
+
+impl ThreadBuilder {
+    /// Creates a builder with the default stack size and priority
+    pub fn new() -> ThreadBuilder {
+        ThreadBuilder { stack_size: DEFAULT_STACK_SIZE, priority: DEFAULT_PRIORITY, name: None }
+    }
+
+    /// Sets the size of the thread's stack in bytes
+    pub fn stack_size(mut self, size: usize) -> ThreadBuilder {
+        self.stack_size = size;
+        self
+    }
+
+    /// Sets the scheduling priority of the thread. Higher values run first.
+    pub fn priority(mut self, priority: u8) -> ThreadBuilder {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets the name of the thread
+    pub fn name(mut self, name: &str) -> ThreadBuilder {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    /// Spawns the thread on the global runtime
+    pub fn spawn<F, T>(self, f: F) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        crate::RUNTIME.spawn_with(self, f)
+    }
+}
+
+impl Default for ThreadBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,9 +1,80 @@
 
+/// The first function every spawned thread runs. It runs the thread's entry point
+/// and then finishes the thread.
+pub unsafe extern "C" fn thread_start() {
+    // A new thread does not return through context_switch, so clean up after any thread
+    // that finished before switching here.
+    crate::RUNTIME.reap();
 
-/// A stack guard which makes it impossible to accidentally return to null
+    if let Some(entry) = crate::RUNTIME.take_entry() {
+        entry();
+    }
+
+    crate::RUNTIME.finish()
+}
+
+/// A stack guard which makes it impossible to accidentally return to null.
+/// A thread that returns here is finished like any other.
 #[no_mangle]
-pub unsafe extern "C" fn guard() {
-    crate::println!("{}", crate::RUNTIME.current_task());
-    // Panic
-    panic!("End of program.")
-}
\ No newline at end of file
+pub unsafe extern "C" fn guard() -> ! {
+    crate::RUNTIME.finish()
+}
+
+/// The interrupt mask bit of the program status register
+#[cfg(feature = "preemptive")]
+const IRQ_MASK: usize = 1 << 7;
+
+/// The thumb state bit of the program status register
+#[cfg(feature = "preemptive")]
+const THUMB_STATE: usize = 1 << 5;
+
+/// Masks interrupts for as long as it is alive so that the tick can not preempt a thread
+/// while the runtime is changing the thread list. Without the preemptive feature this does nothing.
+pub struct CriticalSection {
+    /// Whether interrupts were enabled when the critical section was entered
+    #[cfg(feature = "preemptive")]
+    enabled: bool,
+}
+
+impl CriticalSection {
+    /// Enters a critical section, which is left when the returned value is dropped
+    #[cfg(feature = "preemptive")]
+    pub fn enter() -> CriticalSection {
+        let status: usize;
+        unsafe {
+            core::arch::asm!("mrs {0}, cpsr", "cpsid i", out(reg) status);
+        }
+
+        CriticalSection { enabled: status & IRQ_MASK == 0 }
+    }
+
+    /// Enters a critical section, which is left when the returned value is dropped
+    #[cfg(not(feature = "preemptive"))]
+    pub fn enter() -> CriticalSection {
+        CriticalSection {}
+    }
+}
+
+#[cfg(feature = "preemptive")]
+impl Drop for CriticalSection {
+    /// Unmasks interrupts again if they were enabled when the critical section was entered
+    fn drop(&mut self) {
+        if self.enabled {
+            unsafe {
+                core::arch::asm!("cpsie i");
+            }
+        }
+    }
+}
+
+/// Gets the program status register a new thread starts with. This is the current mode
+/// with interrupts enabled, in ARM state.
+#[cfg(feature = "preemptive")]
+pub fn initial_status() -> usize {
+    let status: usize;
+    unsafe {
+        core::arch::asm!("mrs {0}, cpsr", out(reg) status);
+    }
+
+    status & !(IRQ_MASK | THUMB_STATE)
+}
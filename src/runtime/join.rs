@@ -0,0 +1,84 @@
+// Handles for waiting on spawned threads
+
+use core::cell::UnsafeCell;
+use alloc::sync::Arc;
+
+use super::internal::CriticalSection;
+use super::thread::{ThreadId, WakeupSignal};
+
+/// The shared slot a thread writes its result into when it finishes
+pub(crate) struct Packet<T> {
+    /// The value returned by the thread, if it has finished
+    result: UnsafeCell<Option<T>>,
+}
+
+impl<T> Packet<T> {
+    /// Creates an empty packet
+    pub(crate) fn new() -> Packet<T> {
+        Packet { result: UnsafeCell::new(None) }
+    }
+
+    /// Stores the result of the thread
+    pub(crate) fn set(&self, result: T) {
+        let _cs = CriticalSection::enter();
+        unsafe {
+            *self.result.get() = Some(result);
+        }
+    }
+
+    /// Takes the result of the thread if it has finished
+    fn take(&self) -> Option<T> {
+        let _cs = CriticalSection::enter();
+        unsafe {
+            (*self.result.get()).take()
+        }
+    }
+}
+
+
+/// An owned permission to wait on a thread and get its result
+pub struct JoinHandle<T> {
+    /// The id of the thread
+    id: ThreadId,
+    /// The generation of the thread's slot, so that a killed thread is not confused with
+    /// a new thread in the same slot
+    generation: u32,
+    /// Where the thread will put its result
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Creates a new join handle
+    pub(crate) fn new(id: ThreadId, generation: u32, packet: Arc<Packet<T>>) -> JoinHandle<T> {
+        JoinHandle { id, generation, packet }
+    }
+
+    /// Gets the id of the thread this handle refers to
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Puts the current thread to sleep until the thread has finished, returning its result.
+    /// Returns None if the thread was killed before it could finish.
+    pub fn join(self) -> Option<T> {
+        loop {
+            if let Some(result) = self.packet.take() {
+                return Some(result);
+            }
+
+            // A thread that is gone without leaving a result was killed
+            if !crate::RUNTIME.is_alive(self.id, self.generation) {
+                return None;
+            }
+
+            // The thread wakes us when it finishes
+            crate::RUNTIME.await_wake(WakeupSignal::Join(self.id));
+        }
+    }
+}
+
+
+// The packet is only written by the finishing thread and read by the joining thread, each inside
+// a critical section, so the tick can not switch between them while one is halfway through.
+unsafe impl<T> Send for Packet<T> where T: Send {}
+unsafe impl<T> Sync for Packet<T> where T: Send {}
@@ -0,0 +1,104 @@
+// The kernel thread, which restarts the user thread whenever the competition mode changes
+
+use alloc::boxed::Box;
+
+use super::{Runtime, ThreadBuilder, KERNEL_THREAD, TICK_THREAD, USER_THREAD};
+use super::internal::CriticalSection;
+use crate::hal::hal;
+
+/// How often the kernel thread checks the competition status, in milliseconds
+const POLL_INTERVAL_MS: u32 = 10;
+
+/// The priority of the kernel thread, so that it always gets to check the competition status when it wakes
+const KERNEL_PRIORITY: u8 = u8::MAX;
+
+/// The size of the user thread's stack. User code does most of the work on the robot, so it gets a larger stack.
+const USER_STACK_SIZE: usize = 0x8000;
+
+/// The mode the field controller or competition switch has put the robot in
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompetitionMode {
+    /// The robot is disabled
+    Disabled,
+    /// The robot is running its autonomous routine
+    Autonomous,
+    /// The robot is under driver control. This is also the mode when no competition control is connected.
+    Driver,
+}
+
+impl CompetitionMode {
+    /// Reads the current competition mode
+    pub fn current() -> CompetitionMode {
+        hal().competition_mode()
+    }
+}
+
+impl Runtime {
+    /// Turns the calling thread into the kernel thread. If a `tick` is given the tick thread is started
+    /// once and runs it, and otherwise its slot stays empty. The user thread runs `user` with the current
+    /// competition mode. Every time the mode changes the user thread is killed and restarted with the new mode.
+    /// This must be called from the OS thread and never returns.
+    pub fn start<U, K>(&self, user: U, tick: Option<K>) -> !
+    where
+        U: Fn(CompetitionMode) + Send + Sync + 'static,
+        K: FnOnce() + Send + 'static,
+    {
+        assert!(self.current_task() == KERNEL_THREAD, "The kernel can only be started from the OS thread");
+
+        // The kernel thread needs to win against user code whenever its poll interval is up
+        {
+            let _cs = CriticalSection::enter();
+            let kernel = unsafe { &mut self.threads()[KERNEL_THREAD.0] };
+            kernel.priority = KERNEL_PRIORITY;
+            kernel.base_priority = KERNEL_PRIORITY;
+        }
+
+        // Start the tick thread. It is never restarted.
+        if let Some(tick) = tick {
+            self.spawn_at(TICK_THREAD.0, ThreadBuilder::new().name("tick"), tick)
+                .expect("Failed to start the tick thread");
+        }
+
+        // Start the user thread in the current mode. The kernel never returns, so the closure is
+        // moved onto the heap for good and every user thread borrows it instead of owning a copy,
+        // which would be leaked each time the thread is killed.
+        let user: &'static U = Box::leak(Box::new(user));
+        let mut mode = CompetitionMode::current();
+        self.restart_user(user, mode);
+
+        // Make sure a stuck user thread can not stop the kernel from switching modes
+        #[cfg(feature = "preemptive")]
+        self.enable_preemption();
+
+        loop {
+            self.sleep_ms(POLL_INTERVAL_MS);
+            mode = self.poll_mode(user, mode);
+        }
+    }
+
+    /// Restarts the user thread if the competition mode is no longer `mode`, returning the current mode
+    pub(super) fn poll_mode<U>(&self, user: &'static U, mode: CompetitionMode) -> CompetitionMode
+    where
+        U: Fn(CompetitionMode) + Send + Sync + 'static,
+    {
+        // Restart the user thread on every transition
+        let next = CompetitionMode::current();
+        if next != mode {
+            self.restart_user(user, next);
+        }
+
+        next
+    }
+
+    /// Kills the user thread if it is running and starts it again in the given mode
+    fn restart_user<U>(&self, user: &'static U, mode: CompetitionMode)
+    where
+        U: Fn(CompetitionMode) + Send + Sync + 'static,
+    {
+        // The user thread is never the current thread here, so it is killed straight away
+        self.kill(USER_THREAD);
+
+        self.spawn_at(USER_THREAD.0, ThreadBuilder::new().name("user").stack_size(USER_STACK_SIZE), move || user(mode))
+            .expect("Failed to start the user thread");
+    }
+}
@@ -0,0 +1,142 @@
+// Thread-local storage for green threads
+
+use core::{any::Any, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::{boxed::Box, vec::Vec};
+
+use super::Runtime;
+use super::internal::CriticalSection;
+
+/// The next storage slot to hand out to a key
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// A value stored in one of a thread's storage slots
+pub(crate) struct LocalValue(Box<dyn Any>);
+
+// The value is only ever accessed by the thread that created it, and is dropped either
+// by that thread when it finishes or by the thread that kills it once it can no longer run.
+unsafe impl Send for LocalValue {}
+
+/// A key for a value that every thread has its own copy of, created with `thread_local!`
+pub struct LocalKey<T: 'static> {
+    /// The storage slot plus one, or zero if the key has not been used yet
+    slot: AtomicUsize,
+    /// Creates the value the first time a thread uses the key
+    init: fn() -> T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Creates a key whose value is created with `init` the first time each thread uses it
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> T) -> LocalKey<T> {
+        LocalKey { slot: AtomicUsize::new(0), init }
+    }
+
+    /// Gets the storage slot of the key, assigning one the first time it is used
+    fn slot(&self) -> usize {
+        match self.slot.load(Ordering::Acquire) {
+            0 => {
+                let slot = NEXT_SLOT.fetch_add(1, Ordering::Relaxed);
+
+                // Another thread may have assigned a slot first, in which case that one is used
+                match self.slot.compare_exchange(0, slot + 1, Ordering::AcqRel, Ordering::Acquire) {
+                    Ok(_) => slot,
+                    Err(existing) => existing - 1,
+                }
+            }
+            slot => slot - 1,
+        }
+    }
+
+    /// Calls `f` with a reference to the current thread's value, creating the value first if this
+    /// thread has not used the key before.
+    pub fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let slot = self.slot();
+
+        let value = match crate::RUNTIME.local(slot) {
+            Some(value) => value,
+            None => {
+                // Create the value without holding onto the thread's storage, as the initializer may
+                // use other thread locals or switch threads
+                let value: Box<dyn Any> = Box::new((self.init)());
+                crate::RUNTIME.set_local(slot, LocalValue(value))
+            }
+        };
+
+        // The value is boxed, so it stays in place until this thread finishes
+        f(unsafe { &*value }.downcast_ref::<T>().unwrap())
+    }
+}
+
+impl Runtime {
+    /// Gets a pointer to the current thread's value in a storage slot, if it has one
+    fn local(&self, slot: usize) -> Option<*const dyn Any> {
+        let _cs = CriticalSection::enter();
+        let thread = &unsafe { self.threads() }[self.current_task().0];
+
+        thread.locals.get(slot)?.as_ref().map(|value| &*value.0 as *const dyn Any)
+    }
+
+    /// Stores a value in one of the current thread's storage slots, unless the slot was filled while the
+    /// value was being created. Returns a pointer to the value in the slot.
+    fn set_local(&self, slot: usize, value: LocalValue) -> *const dyn Any {
+        let _cs = CriticalSection::enter();
+        let thread = &mut unsafe { self.threads() }[self.current_task().0];
+
+        if thread.locals.len() <= slot {
+            thread.locals.resize_with(slot + 1, || None);
+        }
+
+        &*thread.locals[slot].get_or_insert(value).0 as *const dyn Any
+    }
+
+    /// Drops the current thread's thread-local values. Destructors may create new values,
+    /// so this repeats until none are left.
+    pub(crate) fn drop_locals(&self) {
+        loop {
+            let locals: Vec<Option<LocalValue>> = {
+                let _cs = CriticalSection::enter();
+                core::mem::take(&mut unsafe { self.threads() }[self.current_task().0].locals)
+            };
+
+            if locals.is_empty() {
+                break;
+            }
+
+            // Drop the values outside of the critical section, as their destructors may switch threads
+            drop(locals);
+        }
+    }
+}
+
+/// Declares statics whose value is separate for every thread, like `std`'s `thread_local!`.
+/// Each thread creates its value the first time it uses the key, and drops it when it finishes.
+/// ```ignore
+/// vexrs::thread_local! {
+///     static COUNTER: core::cell::Cell<u32> = core::cell::Cell::new(0);
+/// }
+///
+/// COUNTER.with(|counter| counter.set(counter.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])*
+        $vis static $name: $crate::runtime::local::LocalKey<$t> = {
+            fn __init() -> $t {
+                $init
+            }
+
+            $crate::runtime::local::LocalKey::new(__init)
+        };
+    };
+}
@@ -1,26 +1,88 @@
 // A simple green threads runtime
 
 use core::{cell::UnsafeCell, sync::atomic::{AtomicUsize, Ordering}};
-use self::thread::ThreadState;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use self::thread::{Task, ThreadId, ThreadState};
+pub(crate) use self::internal::CriticalSection;
+use crate::hal::hal;
 
 /// Private utility functions
 mod internal;
 
+/// Preemptive scheduling driven by the timer interrupt
+#[cfg(feature = "preemptive")]
+mod preempt;
+
 /// A thread implementation
 pub mod thread;
 
+/// Handles for waiting on spawned threads
+pub mod join;
+pub use join::JoinHandle;
+
+/// A builder for configuring threads
+pub mod builder;
+pub use builder::ThreadBuilder;
+
+/// The kernel thread and competition mode handling
+pub mod kernel;
+pub use kernel::CompetitionMode;
+
+/// Listing the threads in the runtime for debugging
+pub mod tasks;
+pub use tasks::{TaskInfo, TaskTable};
+
+/// Per-thread CPU time accounting
+pub mod stats;
+pub use stats::TaskStats;
+
+/// Thread-local storage for green threads
+pub mod local;
+pub use local::LocalKey;
+
+/// Priority inheritance for locks
+pub(crate) mod priority;
+
+/// Tracking the locks each thread holds and the queue it waits in
+pub(crate) mod resource;
+
+/// Tests for the scheduler, run on the host backend
+#[cfg(test)]
+pub(crate) mod tests;
+
 lazy_static::lazy_static! {
     /// The global runtime singleton
     pub static ref RUNTIME: Runtime = Runtime::new();
 }
 
 
-/// We max out the number of threads at 8. This can be changed to support
-// custom needs
-const MAX_THREADS: usize = 8;
+/// The number of threads the thread list has room for before it has to grow
+const INITIAL_THREADS: usize = 8;
+
+/// The kernel thread, which is the OS thread the program starts on
+pub const KERNEL_THREAD: ThreadId = ThreadId(0);
+
+/// The user thread, which is restarted every time the competition mode changes
+pub const USER_THREAD: ThreadId = ThreadId(1);
+
+/// The user tick thread, which is started once by the kernel and never restarted
+pub const TICK_THREAD: ThreadId = ThreadId(2);
+
+/// The number of threads at the start of the list that are reserved for the kernel.
+/// Spawned threads never take these slots.
+const RESERVED_THREADS: usize = 3;
+
+/// The reasons a thread can fail to spawn
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpawnError {
+    /// Every thread in the runtime is in use and the thread list could not grow
+    NoFreeSlot,
+    /// The stack for the new thread could not be allocated
+    StackAllocationFailed,
+}
 
 /// The runtime implementation that maintains a list of threads
-/// as well as information about the current thread and a round robin scheduler.
+/// as well as information about the current thread and a priority based round robin scheduler.
 /// The runtime struct uses interior mutability to allow it to be used as a static singleton.
 pub struct Runtime {
     /// The list of currently running thread
@@ -30,7 +92,10 @@ pub struct Runtime {
     /// Thread one is the user thread and is restarted every time the competition mode changes.
     /// Thread two is the user tick thread and is never killed except when the kernel thread is killed/
     /// All other threads are ignored by the kernel.
-    threads: UnsafeCell<[thread::Thread; MAX_THREADS]>,
+    /// The list grows as more threads are spawned. Each thread is boxed so that it keeps
+    /// its address when the list is reallocated.
+    #[allow(clippy::vec_box)]
+    threads: UnsafeCell<Vec<Box<thread::Thread>>>,
     /// The index of the current thread
     current: core::sync::atomic::AtomicUsize,
 }
@@ -42,14 +107,18 @@ impl Runtime {
         // Create the OS thread
         let mut os = thread::Thread::new();
 
-        // Set it as running
+        // Set it as running. It becomes the kernel thread once the runtime is started.
         os.state = thread::ThreadState::Running;
-
-        // Create the thread list
-        let mut threads: [thread::Thread; MAX_THREADS] = Default::default();
-
-        // Set the OS thread
-        threads[0] = os;
+        os.name = Some(String::from("kernel"));
+        os.stats.switch_in(stats::now());
+
+        // Create the thread list with the OS thread in it, followed by the
+        // reserved user and tick threads. These do not get a stack until they are started.
+        let mut threads = Vec::with_capacity(INITIAL_THREADS);
+        threads.push(Box::new(os));
+        for _ in 1..RESERVED_THREADS {
+            threads.push(Box::new(thread::Thread::new()));
+        }
 
         // Return the runtime
         Runtime {
@@ -58,6 +127,14 @@ impl Runtime {
         }
     }
 
+    /// Gets the thread list as mutable
+    /// # Safety
+    /// Only one thread runs at a time, so the caller must not hold onto the list across a context switch.
+    #[allow(clippy::mut_from_ref, clippy::vec_box)]
+    unsafe fn threads(&self) -> &mut Vec<Box<thread::Thread>> {
+        &mut *self.threads.get()
+    }
+
     /// Switches to a thread with a given index
     unsafe fn context_switch(&self, next: usize, new_state: ThreadState) {
         // Save the current thread
@@ -67,68 +144,153 @@ impl Runtime {
         self.current.store(next, Ordering::SeqCst);
 
         // Get threads as mutable
-        let mut threads = self.threads.get();
+        let threads = self.threads();
+
+        // Report any overflow the tick found, now that we are outside the interrupt handler
+        #[cfg(feature = "preemptive")]
+        if let Some(id) = preempt::take_overflow() {
+            self.check_stack(id);
+        }
+
+        // Make sure the thread we are leaving has not run off the end of its stack
+        self.check_stack(current);
 
         // Set the current thread to the new state and the next one as running
-        (*threads)[current].state = new_state;
-        (*threads)[next].state = ThreadState::Running;
+        threads[current].state = new_state;
+        threads[next].state = ThreadState::Running;
+        self.record_switch(current, next);
+
+        // The next thread gets a full time slice
+        #[cfg(feature = "preemptive")]
+        preempt::reset_slice();
         
         // Context switch to the next thread.
         // Get the next thread's stack pointer
-        let t = (*threads)[next].get_sp();
+        let t = threads[next].get_sp();
         
         // Run the actual context switch
-        (*threads)[current].switch_from(t);
-        
+        threads[current].switch_from(t);
+
+        // We are running again, so free any thread that finished while we were away
+        self.reap();
     }
 
-    /// Gets the next thread to run, returns None if it iterates back to the current thread.
-    fn get_next(&self) -> Option<usize> {
-        let mut i = self.current.load(Ordering::SeqCst);
-        let threads = self.threads.get();
-        loop {
-            i+=1;
-            if i >= unsafe {(*threads).len()} {
-                i = 0;
+    /// Panics if a thread has overflowed its stack
+    fn check_stack(&self, id: usize) {
+        let thread = &unsafe { self.threads() }[id];
+
+        if !thread.check_canary() {
+            match &thread.name {
+                Some(name) => panic!("Stack overflow in thread {} ({})", id, name),
+                None => panic!("Stack overflow in thread {}", id),
             }
-            unsafe {
-                if let ThreadState::Ready = (*threads)[i].state {
-                    return Some(i);
+        }
+    }
+
+    /// Called from the tick interrupt once the running thread has used up its time slice, after its
+    /// context has been pushed onto its stack. Returns the stack pointer of the thread to resume, which is
+    /// the same thread if nothing else of equal or higher priority can run.
+    #[cfg(feature = "preemptive")]
+    unsafe fn preempt(&self, sp: usize) -> usize {
+        let current = self.current.load(Ordering::SeqCst);
+        let threads = self.threads();
+
+        threads[current].set_sp(sp);
+        preempt::reset_slice();
+
+        // The preempted thread is still ready, so it follows the same rules as yielding
+        match self.get_next() {
+            Some(next) if threads[next].priority >= threads[current].priority => {
+                // This runs inside the interrupt handler, so an overflow is only recorded here and reported later
+                if !threads[current].check_canary() {
+                    preempt::record_overflow(current);
                 }
+
+                threads[current].state = ThreadState::Ready;
+                threads[next].state = ThreadState::Running;
+                self.record_switch(current, next);
+                self.current.store(next, Ordering::SeqCst);
+
+                threads[next].get_sp()
             }
-            if i == self.current.load(Ordering::SeqCst) {
-                return None;
+            _ => sp,
+        }
+    }
+
+    /// Starts preempting threads that run for longer than their time slice without yielding
+    #[cfg(feature = "preemptive")]
+    pub fn enable_preemption(&self) {
+        preempt::install();
+    }
+
+    /// Gets the highest priority thread that can run, searching round robin from the current thread
+    /// so that threads of equal priority take turns. Returns None if no other thread can run.
+    fn get_next(&self) -> Option<usize> {
+        let current = self.current.load(Ordering::SeqCst);
+        let threads = unsafe { self.threads() };
+        let len = threads.len();
+        let now = hal().time_ms();
+
+        let mut next: Option<usize> = None;
+        for offset in 1..len {
+            let i = (current + offset) % len;
+            let thread = &threads[i];
+
+            let runnable = match thread.state {
+                ThreadState::Ready => true,
+                // Sleeping threads, and threads waiting with a timeout, become runnable once their deadline has passed
+                ThreadState::AwaitTime(deadline) | ThreadState::AwaitWakeUntil(_, deadline) => now >= deadline,
+                _ => false,
+            };
+
+            // Only take a strictly higher priority so the first thread found wins ties
+            if runnable && next.is_none_or(|n| thread.priority > threads[n].priority) {
+                next = Some(i);
             }
         }
+
+        next
     }
 
     /// Wakes a task up returnign true if successful
-    pub fn wake(&self, id: usize, signal: thread::WakeupSignal) -> bool {
-        // Borrow threads as mut
-        let threads = self.threads.get();
+    pub fn wake(&self, id: ThreadId, signal: thread::WakeupSignal) -> bool {
+        let _cs = CriticalSection::enter();
 
         // If the wake signal is not correct, then do not return
-        if let ThreadState::AwaitWake(s) =  unsafe { (*threads)[id].state }  {
-            if s != signal {
-                return false;
-            }
-        } else {
+        if !self.wake_without_switch(id, signal) {
             return false;
         }
-        
-        // Update the old threads's state
-        unsafe {
-            (*threads)[id].state = ThreadState::Ready;
-        }
 
-        // Switch to it
-        unsafe {
-            self.context_switch(id, ThreadState::Ready)
+        // Switch to it, unless it is suspended or it would be preempting a higher priority thread
+        let threads = unsafe { self.threads() };
+        if threads[id.0].state == ThreadState::Ready
+            && threads[id.0].priority >= threads[self.current.load(Ordering::SeqCst)].priority {
+            unsafe { self.context_switch(id.0, ThreadState::Ready) }
         }
 
         true
     }
 
+    /// Marks a task as ready if it was waiting on a signal, without switching to it. Returns true if successful.
+    pub(crate) fn wake_without_switch(&self, id: ThreadId, signal: thread::WakeupSignal) -> bool {
+        let _cs = CriticalSection::enter();
+
+        unsafe { self.threads() }.get_mut(id.0).is_some_and(|thread| thread.wake(signal))
+    }
+
+    /// Switches to the highest priority thread that can run if it outranks the current thread,
+    /// such as after handing it a lock without switching
+    fn yield_to_higher(&self) {
+        let _cs = CriticalSection::enter();
+        let threads = unsafe { self.threads() };
+
+        if let Some(next) = self.get_next() {
+            if threads[next].priority > threads[self.current.load(Ordering::SeqCst)].priority {
+                unsafe { self.context_switch(next, ThreadState::Ready) }
+            }
+        }
+    }
+
     /// Yields to the next thread
     pub fn yield_next(&self) {
         self.yield_as(ThreadState::Ready);
@@ -139,8 +301,33 @@ impl Runtime {
         self.yield_as(ThreadState::AwaitWake(signal));
     }
 
-    /// Switches to the next context leaving this thread in a specified state
-    fn yield_as(&self, new_state: ThreadState) {
+    /// Puts the thread to sleep until a specific wake signal is recieved or the system time (in milliseconds)
+    /// reaches the deadline. Like `await_wake` this can return early, so the caller should check
+    /// whatever it was waiting for and the time.
+    pub fn await_wake_until(&self, signal: thread::WakeupSignal, deadline: u32) {
+        self.yield_as(ThreadState::AwaitWakeUntil(signal, deadline));
+    }
+
+    /// Puts the current thread to sleep for a number of milliseconds
+    pub fn sleep_ms(&self, ms: u32) {
+        let now = hal().time_ms();
+        self.sleep_until(now.saturating_add(ms));
+    }
+
+    /// Puts the current thread to sleep until the system time (in milliseconds) reaches the deadline
+    pub fn sleep_until(&self, deadline: u32) {
+        // yield_as returns straight away if no other thread can run,
+        // so keep yielding until the deadline has actually passed
+        while hal().time_ms() < deadline {
+            self.yield_as(ThreadState::AwaitTime(deadline));
+        }
+    }
+
+    /// Switches to the next context leaving this thread in a specified state.
+    /// Returns false if there was nothing to switch to.
+    fn yield_as(&self, new_state: ThreadState) -> bool {
+        let _cs = CriticalSection::enter();
+
         // Get the next thread to run
         let next = self.get_next();
 
@@ -148,48 +335,302 @@ impl Runtime {
 
         // If there is a thread to switch to, then switch
         if let Some(n) = next {
+            let threads = unsafe { self.threads() };
+
+            // A thread that is only yielding keeps running if everything else is lower priority
+            if new_state == ThreadState::Ready
+                && threads[n].priority < threads[self.current.load(Ordering::SeqCst)].priority {
+                return false;
+            }
+
             unsafe { self.context_switch(n, new_state); }
+            return true;
         }
         
         // If not, then return doing nothing.
+        false
     }
 
-    /// Gets the current task
-    pub fn current_task(&self) -> usize {
-        self.current.load(Ordering::SeqCst)
+    /// Kills a thread, freeing its stack and waking any thread joining it. Any lock it holds is handed to the
+    /// next task waiting on it, and it is taken out of any queue it waits in. Killing the current thread finishes it.
+    /// Its thread-local values are dropped first, on the calling thread, so any guard stored in one releases its lock.
+    /// Its stack is then freed without being unwound,
+    /// so anything else it owned, such as whatever its entry point captured, is leaked.
+    /// Returns false if there is no thread to kill. The kernel thread can not be killed.
+    pub fn kill(&self, id: ThreadId) -> bool {
+        if id == KERNEL_THREAD {
+            return false;
+        }
+
+        if id == self.current_task() {
+            self.finish();
+        }
+
+        let task = {
+            let _cs = CriticalSection::enter();
+            match unsafe { self.threads() }.get_mut(id.0) {
+                Some(thread) if !matches!(thread.state, ThreadState::Available | ThreadState::Finished) => {
+                    // Keep it from running while its thread-local values are dropped
+                    if thread.state != ThreadState::Suspended {
+                        thread.suspend(thread.state);
+                    }
+                    Task { id, generation: thread.generation }
+                }
+                _ => return false,
+            }
+        };
+
+        // The killed thread's thread-local values are dropped first, so that any guard among them releases
+        // its lock before the rest are handed on. This is outside of the critical section, as their
+        // destructors may switch threads.
+        let locals = {
+            let _cs = CriticalSection::enter();
+            unsafe { self.thread_of(task) }.map(|thread| core::mem::take(&mut thread.locals))
+        };
+        drop(locals);
+
+        let packet = {
+            let _cs = CriticalSection::enter();
+            self.abandon_resources(task);
+
+            unsafe { self.thread_of(task) }.and_then(|thread| {
+                let packet = thread.packet.take();
+                thread.release();
+                packet
+            })
+        };
+
+        // The packet is dropped outside of the critical section, as it may hold the last reference to a result
+        drop(packet);
+
+        self.wake_joiners(id);
+
+        // A task that was handed one of its locks may outrank us
+        self.yield_to_higher();
+        true
     }
 
+    /// Suspends a thread so that it is not scheduled until it is resumed. A suspended thread can still
+    /// be woken, and will be ready to run once resumed. Suspending the current thread switches away from it.
+    /// Returns false if there is no thread to suspend or it is already suspended.
+    pub fn suspend(&self, id: ThreadId) -> bool {
+        if id == self.current_task() {
+            {
+                let _cs = CriticalSection::enter();
+                let threads = unsafe { self.threads() };
+                threads[id.0].suspend(ThreadState::Ready);
+            }
 
-    /// Spawns a new thread
-    pub fn spawn(&self, entry: fn()) {
+            // Nothing can resume us until something else runs, so keep trying until we switch away.
+            // This is outside of the critical section so that the tick is not masked while we spin.
+            while !self.yield_as(ThreadState::Suspended) {}
+            return true;
+        }
 
-        // Find the next available thread
-        let mut pos = self.current.load(Ordering::SeqCst);
-        let threads = self.threads.get();
+        let _cs = CriticalSection::enter();
 
-        loop {
-            pos += 1;
-            if pos >= unsafe { (*threads).len() } {
-                pos = 0;
+        match unsafe { self.threads() }.get_mut(id.0) {
+            Some(thread) if !matches!(thread.state, ThreadState::Available | ThreadState::Finished | ThreadState::Suspended) => {
+                thread.suspend(thread.state);
+                true
             }
+            _ => false,
+        }
+    }
 
-            // If it is the same as the current, then to threads were found and we can not spawn the thread
-            if pos == self.current.load(Ordering::SeqCst) {
-                return;
-            }
+    /// Resumes a suspended thread, returning false if it was not suspended
+    pub fn resume(&self, id: ThreadId) -> bool {
+        let _cs = CriticalSection::enter();
 
-            if unsafe { (*threads)[pos].state == ThreadState::Available } {
-                break;
+        match unsafe { self.threads() }.get_mut(id.0) {
+            Some(thread) if thread.state == ThreadState::Suspended => {
+                thread.resume();
+                true
             }
+            _ => false,
         }
+    }
+
+    /// Returns true if the thread in a slot is still the one from the given generation and has not finished
+    pub(crate) fn is_alive(&self, id: ThreadId, generation: u32) -> bool {
+        let _cs = CriticalSection::enter();
+
+        match unsafe { self.threads() }.get(id.0) {
+            Some(thread) => thread.generation == generation
+                && !matches!(thread.state, ThreadState::Available | ThreadState::Finished),
+            None => false,
+        }
+    }
+
+    /// Gets the largest number of bytes of stack a thread has used so far,
+    /// or None if the thread is not running.
+    pub fn stack_high_water_mark(&self, id: ThreadId) -> Option<usize> {
+        let _cs = CriticalSection::enter();
+        let threads = unsafe { self.threads() };
+        let thread = threads.get(id.0)?;
+
+        if thread.state == ThreadState::Available {
+            return None;
+        }
+
+        Some(thread.stack_high_water_mark())
+    }
+
+    /// Gets the current task
+    pub fn current_task(&self) -> ThreadId {
+        ThreadId(self.current.load(Ordering::SeqCst))
+    }
+
+    /// Gets the current task along with its generation, to tell it apart from later threads in its slot
+    pub(crate) fn current(&self) -> Task {
+        let _cs = CriticalSection::enter();
+        let id = self.current.load(Ordering::SeqCst);
+
+        Task { id: ThreadId(id), generation: unsafe { self.threads() }[id].generation }
+    }
+
+
+    /// Spawns a new thread with the default priority
+    pub fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        ThreadBuilder::new().spawn(f)
+    }
+
+    /// Spawns a new thread with a given priority. Higher priority threads always run
+    /// before lower priority ones when they are ready.
+    pub fn spawn_with_priority<F, T>(&self, f: F, priority: u8) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        ThreadBuilder::new().priority(priority).spawn(f)
+    }
+
+    /// Spawns a new thread configured by a builder
+    fn spawn_with<F, T>(&self, builder: ThreadBuilder, f: F) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _cs = CriticalSection::enter();
+
+        // Find the next available thread, growing the thread list if they are all in use
+        let pos = match self.find_available() {
+            Some(pos) => pos,
+            None => self.grow()?,
+        };
+
+        self.spawn_at(pos, builder, f)
+    }
+
+    /// Spawns a new thread in a specific available slot of the thread list
+    fn spawn_at<F, T>(&self, pos: usize, builder: ThreadBuilder, f: F) -> Result<JoinHandle<T>, SpawnError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _cs = CriticalSection::enter();
+        let threads = unsafe { self.threads() };
+
+        // Wrap the closure so that its result ends up in the join handle's packet
+        let packet = Arc::new(join::Packet::new());
+        let entry = Box::new(move || {
+            let result = f();
+            crate::RUNTIME.set_result::<T>(result);
+        });
 
         // Re-initialize the thread
-        unsafe {
-            (*threads)[pos].initialize(entry);
+        threads[pos].initialize(entry, builder.priority, builder.stack_size, builder.name)?;
+        threads[pos].packet = Some(packet.clone());
+
+        Ok(JoinHandle::new(ThreadId(pos), threads[pos].generation, packet))
+    }
+
+    /// Puts the current thread's result into its packet for its join handle to take
+    fn set_result<T: Send + 'static>(&self, result: T) {
+        let packet = {
+            let _cs = CriticalSection::enter();
+            let threads = unsafe { self.threads() };
+            threads[self.current.load(Ordering::SeqCst)].packet.take()
+        };
+
+        // The packet is dropped here, outside of the critical section, as it may hold the last
+        // reference to the result if the join handle is gone
+        if let Some(packet) = packet.as_ref().and_then(|packet| packet.downcast_ref::<join::Packet<T>>()) {
+            packet.set(result);
         }
+    }
 
-        
-    } 
+    /// Finds the next available thread after the current one, skipping the threads reserved for the kernel
+    fn find_available(&self) -> Option<usize> {
+        let current = self.current.load(Ordering::SeqCst);
+        let threads = unsafe { self.threads() };
+        let len = threads.len();
+
+        (1..len)
+            .map(|offset| (current + offset) % len)
+            .find(|i| *i >= RESERVED_THREADS && threads[*i].state == ThreadState::Available)
+    }
+
+    /// Adds a new available thread to the end of the thread list, returning its index
+    fn grow(&self) -> Result<usize, SpawnError> {
+        let threads = unsafe { self.threads() };
+
+        threads.try_reserve(1).map_err(|_| SpawnError::NoFreeSlot)?;
+        threads.push(Box::new(thread::Thread::new()));
+        Ok(threads.len() - 1)
+    }
+
+    /// Takes the entry point of the current thread so it can be run
+    fn take_entry(&self) -> Option<Box<dyn FnOnce() + Send>> {
+        let _cs = CriticalSection::enter();
+        let threads = unsafe { self.threads() };
+        threads[self.current.load(Ordering::SeqCst)].entry.take()
+    }
+
+    /// Frees the stacks of finished threads so that their slots can be reused.
+    /// A finished thread can not free its own stack as it is still running on it,
+    /// so this is done by whichever thread runs next.
+    fn reap(&self) {
+        let _cs = CriticalSection::enter();
+        let current = self.current.load(Ordering::SeqCst);
+        let threads = unsafe { self.threads() };
+
+        for (i, thread) in threads.iter_mut().enumerate() {
+            if i != current && thread.state == ThreadState::Finished {
+                thread.release();
+            }
+        }
+    }
+
+    /// Marks every thread joining a thread as ready. There is no need to switch to them straight away
+    /// as the thread is going away either way.
+    fn wake_joiners(&self, id: ThreadId) {
+        let _cs = CriticalSection::enter();
+
+        for thread in unsafe { self.threads() }.iter_mut() {
+            thread.wake(thread::WakeupSignal::Join(id));
+        }
+    }
+
+    /// Finishes the current thread, waking any thread that is joining it
+    fn finish(&self) -> ! {
+        // Run the thread-local destructors while this thread can still run code
+        self.drop_locals();
+
+        // Give up any locks that were never released, such as when the thread killed itself while holding them
+        self.abandon_resources(self.current());
+        self.wake_joiners(self.current_task());
+
+        // A finished thread is never scheduled again, and the next thread to run reaps it.
+        // If nothing else can run yet, keep trying until something can.
+        loop {
+            self.yield_as(ThreadState::Finished);
+        }
+    }
 }
 
 impl Default for Runtime {
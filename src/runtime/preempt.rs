@@ -0,0 +1,164 @@
+// Preemptive scheduling driven by the timer interrupt.
+// The interrupt handler mirrors the FreeRTOS Cortex-A9 port that PROS uses on the V5: libv5rt
+// still handles every interrupt, but on the way out the handler may push the interrupted thread's
+// context onto its own stack in the same layout as Thread::switch_from and resume a different thread.
+// As in that port, the floating point registers are saved around libv5rt's handler and with the context.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+use super::internal::CriticalSection;
+
+/// The number of ticks (milliseconds) a thread may run before it is preempted
+const TIME_SLICE_TICKS: u32 = 5;
+
+/// The priority of the tick interrupt, the lowest usable priority of the interrupt controller
+const TICK_PRIORITY: u32 = 30 << 3;
+
+/// The interrupt acknowledge register of the Zynq's interrupt controller
+const ICCIAR: usize = 0xF8F0_010C;
+
+/// The end of interrupt register of the Zynq's interrupt controller
+const ICCEOIR: usize = 0xF8F0_0110;
+
+/// Set by the tick when the running thread has used up its time slice.
+/// The interrupt handler switches threads on its way out when this is set.
+static SWITCH_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The number of ticks the running thread has used of its time slice
+static SLICE_TICKS: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the vector table and tick have been installed
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// A thread the tick found had overflowed its stack, or usize::MAX if there is none.
+/// Panicking inside the interrupt handler would never hand the interrupt back to libv5rt,
+/// so the overflow is reported the next time the runtime switches threads outside of it.
+static OVERFLOWED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+extern "C" {
+    /// The vector table defined below
+    fn vexrs_vector_table();
+}
+
+/// Installs the vector table and starts the tick. Does nothing if they are already installed.
+pub fn install() {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let _cs = CriticalSection::enter();
+
+    unsafe {
+        // Point the vector base address register at our table
+        core::arch::asm!(
+            "mcr p15, 0, {0}, c12, c0, 0",
+            "isb",
+            in(reg) vexrs_vector_table as *const () as usize,
+        );
+
+        crate::libv5rt::vexSystemTimerReinitForRtos(TICK_PRIORITY, Some(tick));
+    }
+}
+
+/// Starts a new time slice. Called whenever the runtime switches threads.
+pub fn reset_slice() {
+    SLICE_TICKS.store(0, Ordering::SeqCst);
+    SWITCH_PENDING.store(false, Ordering::SeqCst);
+}
+
+/// Records that a thread has overflowed its stack, to be reported outside the interrupt handler
+pub fn record_overflow(id: usize) {
+    OVERFLOWED.store(id, Ordering::SeqCst);
+}
+
+/// Takes the thread the tick found had overflowed its stack, if there is one
+pub fn take_overflow() -> Option<usize> {
+    match OVERFLOWED.swap(usize::MAX, Ordering::SeqCst) {
+        usize::MAX => None,
+        id => Some(id),
+    }
+}
+
+/// Called by libv5rt on every tick, in interrupt context
+unsafe extern "C" fn tick(_data: *mut libc::c_void) {
+    crate::libv5rt::vexSystemTimerClearInterrupt();
+
+    if SLICE_TICKS.fetch_add(1, Ordering::SeqCst) + 1 >= TIME_SLICE_TICKS {
+        SWITCH_PENDING.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Called by the interrupt handler with the stack pointer of the preempted thread once its
+/// context has been pushed. Returns the stack pointer of the thread to resume.
+unsafe extern "C" fn preempt_switch(sp: usize) -> usize {
+    crate::RUNTIME.preempt(sp)
+}
+
+core::arch::global_asm!(
+    ".section .text.vexrs_vectors, \"ax\"",
+    ".arm",
+
+    // The vector table. Everything except interrupts goes straight to libv5rt.
+    ".balign 32",
+    ".global vexrs_vector_table",
+    "vexrs_vector_table:",
+    "b vexSystemBoot",
+    "b vexSystemUndefinedException",
+    "b vexSystemSWInterrupt",
+    "b vexSystemPrefetchAbortInterrupt",
+    "b vexSystemDataAbortInterrupt",
+    "nop",
+    "b vexrs_irq_handler",
+    "b vexSystemFIQInterrupt",
+
+    // The interrupt handler
+    "vexrs_irq_handler:",
+    "sub lr, lr, #4", // Return to the interrupted instruction
+    "push {{r0-r3, r12, lr}}", // Save the registers the handler may clobber on the interrupt stack
+    "ldr r1, ={iar}",
+    "ldr r0, [r1]", // Acknowledge the interrupt, getting its id
+    "vmrs r1, fpscr",
+    "push {{r0, r1}}", // Keep the id and the floating point status register, which also keeps the stack aligned
+    "vpush {{d0-d15}}", // libv5rt may use the floating point registers, so save the interrupted thread's
+    "vpush {{d16-d31}}",
+    "bl vexSystemApplicationIRQHandler", // Let libv5rt handle the interrupt, which runs the tick
+    "vpop {{d16-d31}}",
+    "vpop {{d0-d15}}",
+    "pop {{r0, r1}}",
+    "vmsr fpscr, r1",
+    "ldr r1, ={eoir}",
+    "str r0, [r1]", // Signal the end of the interrupt
+    "ldr r0, ={pending}",
+    "ldrb r1, [r0]",
+    "cmp r1, #0",
+    "bne 1f",
+    "pop {{r0-r3, r12, lr}}",
+    "movs pc, lr", // Nothing to switch, so return to the interrupted thread restoring its status register
+
+    "1:",
+    "mov r1, #0",
+    "strb r1, [r0]", // Clear the pending switch
+    "pop {{r0-r3, r12, lr}}", // Restore the interrupted thread's registers
+    "srsdb sp!, #0x1f", // Push the return address and status register onto the thread's stack
+    "cps #0x1f", // Switch to system mode, where the thread's stack is
+    "push {{r0-r12, lr}}", // Push the rest of the context in the same layout as switch_from
+    "vpush {{d16-d31}}", // Push the floating point registers and status register
+    "vpush {{d0-d15}}",
+    "vmrs r0, fpscr",
+    "push {{r0}}",
+    "mov r0, sp", // Pass the thread's stack pointer to the scheduler
+    "bic sp, sp, #7", // Align the stack for the call
+    "bl {switch}", // Save the thread and pick the next one
+    "mov sp, r0", // Load the next thread's stack pointer
+    "pop {{r0}}",
+    "vmsr fpscr, r0",
+    "vpop {{d0-d15}}",
+    "vpop {{d16-d31}}",
+    "pop {{r0-r12, lr}}",
+    "rfeia sp!", // Pop the program counter and status register, resuming the next thread
+
+    iar = const ICCIAR,
+    eoir = const ICCEOIR,
+    pending = sym SWITCH_PENDING,
+    switch = sym preempt_switch,
+);
@@ -0,0 +1,53 @@
+// Priority inheritance for locks, so that a low priority thread holding a lock can not keep
+// a higher priority thread waiting on it behind every thread in between
+
+use super::Runtime;
+use super::internal::CriticalSection;
+use super::thread::Task;
+
+impl Runtime {
+    /// Gets the priority a task is running at, or None if it is gone
+    pub(crate) fn priority_of(&self, task: Task) -> Option<u8> {
+        let _cs = CriticalSection::enter();
+
+        unsafe { self.thread_of(task) }.map(|thread| thread.priority)
+    }
+
+    /// Works out a task's priority again after the locks it holds, or the tasks waiting on them, have changed.
+    /// A task runs at its own priority or that of the highest priority task waiting on a lock it still holds,
+    /// whichever is higher. If it is itself waiting on a lock, the holder of that lock is worked out again too,
+    /// and so on along the chain, so that a task waiting on a waiter still lends its priority to the end of the chain.
+    pub(crate) fn update_priority(&self, task: Task) {
+        let _cs = CriticalSection::enter();
+
+        // The chain can only visit every thread once, unless the tasks in it are deadlocked on each other
+        let mut next = Some(task);
+        for _ in 0..unsafe { self.threads() }.len() {
+            let Some(task) = next else {
+                break;
+            };
+
+            // The thread is looked up again for every lock, as asking a lock for its waiters looks at their threads
+            let Some(mut priority) = unsafe { self.thread_of(task) }.map(|thread| thread.base_priority) else {
+                break;
+            };
+            let mut index = 0;
+            while let Some(held) = unsafe { self.thread_of(task) }.and_then(|thread| thread.held.get(index).copied()) {
+                priority = priority.max(unsafe { held.get() }.waiter_priority().unwrap_or(0));
+                index += 1;
+            }
+
+            let Some(thread) = (unsafe { self.thread_of(task) }) else {
+                break;
+            };
+
+            // Nothing further along the chain changes if this task's priority did not
+            if thread.priority == priority {
+                break;
+            }
+
+            thread.priority = priority;
+            next = thread.waiting_in.and_then(|waiting_in| unsafe { waiting_in.get() }.holder());
+        }
+    }
+}
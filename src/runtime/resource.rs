@@ -0,0 +1,118 @@
+// Tracks the locks each thread holds and the queue it waits in, so that a killed thread gives them up
+// instead of leaving them held or leaving its place in the queue to whatever reuses its slot
+
+use super::Runtime;
+use super::internal::CriticalSection;
+use super::thread::{Task, Thread, ThreadState};
+
+/// Something a thread can hold or wait on, such as a lock
+pub(crate) trait Resource {
+    /// Gives up everything a killed task held in the resource and takes it out of the queue,
+    /// handing on what it held to the tasks waiting. This is called inside a critical section
+    /// and must not switch threads, as the killed thread is still being torn down.
+    fn abandon(&self, task: Task);
+
+    /// Gets the task the waiters lend their priority to, if the resource has a single holder
+    fn holder(&self) -> Option<Task>;
+
+    /// Gets the highest priority of the tasks waiting for the holder to give the resource up,
+    /// or None if nobody is waiting or the resource does not lend priority
+    fn waiter_priority(&self) -> Option<u8>;
+}
+
+/// A resource a thread holds or waits on. Every wait borrows the resource, and a resource
+/// takes itself off every thread's records when it is dropped, so a record never outlives it.
+#[derive(Clone, Copy)]
+pub(crate) struct ResourceRef(*const dyn Resource);
+
+impl ResourceRef {
+    /// Creates a record of a resource
+    pub(crate) fn new<R: Resource + 'static>(resource: &R) -> ResourceRef {
+        ResourceRef(resource as *const dyn Resource)
+    }
+
+    /// Returns true if both records are of the same resource
+    fn is(&self, other: ResourceRef) -> bool {
+        core::ptr::addr_eq(self.0, other.0)
+    }
+
+    /// Gets the resource
+    /// # Safety
+    /// The caller must be in a critical section, and the record must still be on a live thread's list.
+    pub(super) unsafe fn get(&self) -> &dyn Resource {
+        &*self.0
+    }
+}
+
+// Records are only followed inside critical sections, while the resource is still borrowed
+unsafe impl Send for ResourceRef {}
+
+impl Runtime {
+    /// Gets the thread a task runs in, or None if the task is gone and its slot is free or reused
+    /// # Safety
+    /// The caller must be in a critical section and must not keep the reference across a context switch.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn thread_of(&self, task: Task) -> Option<&mut Thread> {
+        self.threads().get_mut(task.id.0)
+            .map(|thread| &mut **thread)
+            .filter(|thread| thread.generation == task.generation
+                && !matches!(thread.state, ThreadState::Available | ThreadState::Finished))
+    }
+
+    /// Records that a task has taken a lock. This must be called inside a critical section.
+    pub(crate) fn hold(&self, task: Task, resource: ResourceRef) {
+        if let Some(thread) = unsafe { self.thread_of(task) } {
+            thread.held.push(resource);
+        }
+    }
+
+    /// Records that a task has given up a lock it took. This must be called inside a critical section.
+    pub(crate) fn unhold(&self, task: Task, resource: ResourceRef) {
+        if let Some(thread) = unsafe { self.thread_of(task) } {
+            if let Some(position) = thread.held.iter().position(|held| held.is(resource)) {
+                thread.held.swap_remove(position);
+            }
+        }
+    }
+
+    /// Records the queue a task is waiting in, or that it has stopped waiting.
+    /// This must be called inside a critical section.
+    pub(crate) fn wait_in(&self, task: Task, resource: Option<ResourceRef>) {
+        if let Some(thread) = unsafe { self.thread_of(task) } {
+            thread.waiting_in = resource;
+        }
+    }
+
+    /// Takes a resource that is going away off every thread's records. Nothing can be waiting in it,
+    /// as waiting borrows it, but a thread still holds it if the guard was leaked.
+    pub(crate) fn forget_resource(&self, resource: ResourceRef) {
+        let _cs = CriticalSection::enter();
+
+        for thread in unsafe { self.threads() }.iter_mut() {
+            thread.held.retain(|held| !held.is(resource));
+            if thread.waiting_in.is_some_and(|waiting| waiting.is(resource)) {
+                thread.waiting_in = None;
+            }
+        }
+    }
+
+    /// Gives up every lock a thread holds and takes it out of the queue it waits in.
+    /// Called when it is killed, or finishes with locks it never released.
+    pub(crate) fn abandon_resources(&self, task: Task) {
+        let _cs = CriticalSection::enter();
+
+        // Each resource is taken off the thread's records before it is told,
+        // as it updates the records of the tasks it hands itself on to
+        loop {
+            let next = match unsafe { self.thread_of(task) } {
+                Some(thread) => thread.waiting_in.take().or_else(|| thread.held.pop()),
+                None => None,
+            };
+
+            match next {
+                Some(resource) => unsafe { resource.get() }.abandon(task),
+                None => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Per-thread CPU time accounting
+
+use core::fmt;
+use alloc::{string::String, vec::Vec};
+
+use super::{JoinHandle, Runtime, SpawnError, ThreadBuilder};
+use super::internal::CriticalSection;
+use crate::hal::hal;
+use super::thread::{ThreadId, ThreadState};
+
+/// Gets the current time in microseconds
+pub(crate) fn now() -> u64 {
+    hal().time_us()
+}
+
+/// The scheduling statistics the runtime keeps for a thread, in microseconds
+#[derive(Clone, Copy, Default)]
+pub struct ThreadStats {
+    /// The total time the thread has spent running
+    pub run_time: u64,
+    /// The number of times the thread has been switched to
+    pub switches: u32,
+    /// The longest time the thread has run without being switched away from
+    pub longest_run: u64,
+    /// The time the thread was last switched to, or None if it has never run
+    pub last_scheduled: Option<u64>,
+}
+
+impl ThreadStats {
+    /// Records the thread being switched to
+    pub(crate) fn switch_in(&mut self, now: u64) {
+        self.switches = self.switches.wrapping_add(1);
+        self.last_scheduled = Some(now);
+    }
+
+    /// Records the thread being switched away from
+    pub(crate) fn switch_out(&mut self, now: u64) {
+        if let Some(start) = self.last_scheduled {
+            let run = now.saturating_sub(start);
+            self.run_time += run;
+            self.longest_run = self.longest_run.max(run);
+        }
+    }
+}
+
+/// A snapshot of a thread's statistics, taken when the stats were requested. Times are in microseconds.
+pub struct TaskStats {
+    /// The id of the thread
+    pub id: ThreadId,
+    /// The name of the thread, if it was given one
+    pub name: Option<String>,
+    /// The total time the thread has spent running, including its current run
+    pub run_time: u64,
+    /// The number of times the thread has been switched to
+    pub switches: u32,
+    /// The longest time the thread has run without being switched away from, including its current run
+    pub longest_run: u64,
+    /// How long ago the thread was last switched to, or None if it has never run
+    pub since_scheduled: Option<u64>,
+}
+
+impl TaskStats {
+    /// The column headings matching the rows written by the `Display` implementation
+    pub const HEADER: &'static str = "  ID NAME                 RUN(us) SWITCHES LONGEST(us)    SINCE(us)";
+}
+
+impl fmt::Display for TaskStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{:>4} {:<16} {:>11} {:>8} {:>11} ",
+            self.id.as_usize(), self.name.as_deref().unwrap_or("-"),
+            self.run_time, self.switches, self.longest_run,
+        )?;
+
+        match self.since_scheduled {
+            Some(since) => write!(f, "{:>12}", since),
+            None => write!(f, "{:>12}", "-"),
+        }
+    }
+}
+
+/// An iterator over the statistics of the threads that were in use when it was created
+pub struct Stats {
+    /// The snapshots left to return
+    stats: alloc::vec::IntoIter<TaskStats>,
+}
+
+impl Iterator for Stats {
+    type Item = TaskStats;
+
+    fn next(&mut self) -> Option<TaskStats> {
+        self.stats.next()
+    }
+}
+
+impl Runtime {
+    /// Records a switch from one thread to another in their statistics
+    pub(crate) fn record_switch(&self, current: usize, next: usize) {
+        let threads = unsafe { self.threads() };
+        let now = now();
+
+        threads[current].stats.switch_out(now);
+        threads[next].stats.switch_in(now);
+    }
+
+    /// Gets a snapshot of the statistics of every thread that is in use. The running thread's
+    /// current run is counted up to the time of the snapshot.
+    pub fn stats(&self) -> Stats {
+        let _cs = CriticalSection::enter();
+        let now = now();
+
+        let stats: Vec<TaskStats> = unsafe { self.threads() }.iter().enumerate()
+            .filter(|(_, thread)| thread.state != ThreadState::Available)
+            .map(|(id, thread)| {
+                let mut stats = thread.stats;
+                if thread.state == ThreadState::Running {
+                    stats.switch_out(now);
+                }
+
+                TaskStats {
+                    id: ThreadId(id),
+                    name: thread.name.clone(),
+                    run_time: stats.run_time,
+                    switches: stats.switches,
+                    longest_run: stats.longest_run,
+                    since_scheduled: stats.last_scheduled.map(|start| now.saturating_sub(start)),
+                }
+            })
+            .collect();
+
+        Stats { stats: stats.into_iter() }
+    }
+
+    /// Prints a table of the statistics of every thread that is in use over serial
+    pub fn print_stats(&self) {
+        crate::println!("{}", TaskStats::HEADER);
+
+        for stats in self.stats() {
+            crate::println!("{}", stats);
+        }
+    }
+
+    /// Spawns a thread that prints the statistics table over serial every `interval_ms` milliseconds
+    pub fn stream_stats(&self, interval_ms: u32) -> Result<JoinHandle<()>, SpawnError> {
+        ThreadBuilder::new().name("stats").spawn(move || {
+            let mut deadline = hal().time_ms();
+
+            loop {
+                crate::RUNTIME.print_stats();
+
+                // Sleep until a fixed deadline so that the time spent printing does not add up
+                deadline = deadline.wrapping_add(interval_ms);
+                crate::RUNTIME.sleep_until(deadline);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+    use std::string::String;
+
+    use crate::RUNTIME;
+    use crate::hal::Mock;
+    use crate::runtime::tests::{serial, spawn, use_mock};
+    use super::TaskStats;
+
+    /// Takes what has been printed over the mock's serial link. Each printed line is carried
+    /// verbatim inside its serial packet, so the lines can be searched for in the output.
+    fn printed(mock: &Mock) -> String {
+        String::from_utf8_lossy(&mock.take_serial_output()).into_owned()
+    }
+
+    #[test]
+    fn print_stats_writes_a_row_for_each_thread() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        // The worker runs for exactly 3ms of mock time before switching back
+        let handle = spawn(|| {
+            MOCK.advance_ms(3);
+            RUNTIME.yield_next();
+        });
+        RUNTIME.yield_next();
+        MOCK.take_serial_output();
+
+        RUNTIME.print_stats();
+        let output = printed(&MOCK);
+
+        let row = format!("{:>4} {:<16} {:>11} {:>8} {:>11} {:>12}", handle.id().as_usize(), "-", 3000, 1, 3000, 3000);
+        assert!(output.contains(TaskStats::HEADER), "{}", output);
+        assert!(output.contains(&row), "{}", output);
+        assert_eq!(output.lines().filter(|line| !line.contains("ID NAME")).count(), RUNTIME.stats().count());
+
+        handle.join();
+    }
+
+    #[test]
+    fn stream_stats_prints_at_every_interval() {
+        let _serial = serial();
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+        MOCK.take_serial_output();
+
+        let handle = RUNTIME.stream_stats(100).unwrap();
+        let tables = || printed(&MOCK).matches(TaskStats::HEADER).count();
+
+        RUNTIME.yield_next();
+        assert_eq!(tables(), 1);
+
+        // Nothing more is printed until the interval has passed
+        MOCK.advance_ms(99);
+        RUNTIME.yield_next();
+        assert_eq!(tables(), 0);
+
+        MOCK.advance_ms(1);
+        RUNTIME.yield_next();
+        let output = printed(&MOCK);
+        assert_eq!(output.matches(TaskStats::HEADER).count(), 1);
+        assert!(output.lines().any(|line| line.split_whitespace().nth(1) == Some("stats")), "{}", output);
+
+        assert!(RUNTIME.kill(handle.id()));
+    }
+}
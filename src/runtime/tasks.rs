@@ -0,0 +1,142 @@
+// Listing the threads in the runtime for debugging
+
+use core::fmt;
+use alloc::{format, string::String, vec::Vec};
+
+use super::Runtime;
+use super::internal::CriticalSection;
+use super::thread::{ThreadId, ThreadState};
+
+/// A snapshot of a thread's state, taken when the task list was requested
+pub struct TaskInfo {
+    /// The id of the thread
+    pub id: ThreadId,
+    /// The name of the thread, if it was given one
+    pub name: Option<String>,
+    /// The state the thread was in
+    pub state: ThreadState,
+    /// The scheduling priority of the thread
+    pub priority: u8,
+    /// The size of the thread's stack in bytes
+    pub stack_size: usize,
+    /// The largest number of bytes of stack the thread had used
+    pub stack_usage: usize,
+}
+
+impl TaskInfo {
+    /// Writes the snapshot as a row of the task table, with the state padded to the given width
+    fn write_row(&self, f: &mut fmt::Formatter<'_>, state_width: usize) -> fmt::Result {
+        write!(
+            f, "{:>4} {:<16} {:<state_width$} {:>3} {:>7} {:>7}",
+            self.id.as_usize(), self.name.as_deref().unwrap_or("-"), self.state,
+            self.priority, self.stack_size, self.stack_usage,
+        )
+    }
+}
+
+impl fmt::Display for TaskInfo {
+    /// Writes the snapshot as a single row. Use a `TaskTable` to line up the rows of several snapshots.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_row(f, 0)
+    }
+}
+
+/// A table of task snapshots, with column headings and the state column as wide as its longest state
+pub struct TaskTable(pub Vec<TaskInfo>);
+
+impl fmt::Display for TaskTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state_width = self.0.iter()
+            .map(|task| format!("{}", task.state).len())
+            .fold("STATE".len(), usize::max);
+
+        write!(f, "  ID NAME             {:<state_width$} PRI   STACK    USED", "STATE")?;
+
+        for task in &self.0 {
+            writeln!(f)?;
+            task.write_row(f, state_width)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An iterator over the threads that were in use when it was created
+pub struct Tasks {
+    /// The snapshots left to return
+    tasks: alloc::vec::IntoIter<TaskInfo>,
+}
+
+impl Iterator for Tasks {
+    type Item = TaskInfo;
+
+    fn next(&mut self) -> Option<TaskInfo> {
+        self.tasks.next()
+    }
+}
+
+impl Runtime {
+    /// Gets a snapshot of every thread that is in use. The snapshot is taken all at once,
+    /// so threads that start or finish while iterating are not reflected in it.
+    pub fn tasks(&self) -> Tasks {
+        let _cs = CriticalSection::enter();
+
+        let tasks: Vec<TaskInfo> = unsafe { self.threads() }.iter().enumerate()
+            .filter(|(_, thread)| thread.state != ThreadState::Available)
+            .map(|(id, thread)| TaskInfo {
+                id: ThreadId(id),
+                name: thread.name.clone(),
+                state: thread.state,
+                priority: thread.priority,
+                stack_size: thread.stack_size(),
+                stack_usage: thread.stack_high_water_mark(),
+            })
+            .collect();
+
+        Tasks { tasks: tasks.into_iter() }
+    }
+
+    /// Prints a table of every thread that is in use over serial, like `ps`
+    pub fn print_tasks(&self) {
+        crate::println!("{}", TaskTable(self.tasks().collect()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{format, string::String, vec};
+
+    use super::{TaskInfo, TaskTable};
+    use crate::runtime::thread::{ThreadId, ThreadState, WakeupSignal};
+
+    /// Makes a snapshot of a thread with a 4 KiB stack
+    fn task(id: usize, name: Option<&str>, state: ThreadState) -> TaskInfo {
+        TaskInfo { id: ThreadId(id), name: name.map(String::from), state, priority: 8, stack_size: 4096, stack_usage: 320 }
+    }
+
+    #[test]
+    fn table_lines_up_on_the_longest_state() {
+        let table = TaskTable(vec![
+            task(0, Some("main"), ThreadState::Running),
+            task(3, None, ThreadState::AwaitWakeUntil(WakeupSignal::MutexRelease(0x2000_1f40), 1500)),
+            task(12, Some("worker"), ThreadState::AwaitWake(WakeupSignal::Join(ThreadId(3)))),
+        ]);
+
+        assert_eq!(format!("{}", table), concat!(
+            "  ID NAME             STATE                                          PRI   STACK    USED\n",
+            "   0 main             Running                                          8    4096     320\n",
+            "   3 -                AwaitWakeUntil(MutexRelease(0x20001f40), 1500)   8    4096     320\n",
+            "  12 worker           AwaitWake(Join(3))                               8    4096     320",
+        ));
+    }
+
+    #[test]
+    fn state_column_is_at_least_as_wide_as_its_heading() {
+        let table = TaskTable(vec![task(1, None, ThreadState::Ready)]);
+
+        assert_eq!(format!("{}", table), concat!(
+            "  ID NAME             STATE PRI   STACK    USED\n",
+            "   1 -                Ready   8    4096     320",
+        ));
+    }
+}
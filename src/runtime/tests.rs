@@ -0,0 +1,601 @@
+// Tests for the scheduler, run on the host backend
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::vec::Vec;
+use std::vec;
+
+use crate::RUNTIME;
+use super::{CompetitionMode, JoinHandle, ThreadBuilder, USER_THREAD};
+use super::thread::{Task, ThreadId, ThreadState, WakeupSignal};
+use crate::hal::{self, Clock, Mock};
+
+/// The size of the stacks test threads are given. Host code needs much more stack than the V5.
+const TEST_STACK_SIZE: usize = 0x10000;
+
+/// Makes tests take turns with the global runtime, as they are run from several OS threads at once.
+/// Each test must leave every thread it spawned finished before it returns.
+pub(crate) fn serial() -> MutexGuard<'static, ()> {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Spawns a thread with a stack large enough for the host
+pub(crate) fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    ThreadBuilder::new().stack_size(TEST_STACK_SIZE).spawn(f).unwrap()
+}
+
+/// Spawns a thread with a priority and a stack large enough for the host
+pub(crate) fn spawn_with_priority<F, T>(f: F, priority: u8) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    ThreadBuilder::new().stack_size(TEST_STACK_SIZE).priority(priority).spawn(f).unwrap()
+}
+
+/// Gets the current priority of a thread
+pub(crate) fn priority_of(id: ThreadId) -> u8 {
+    RUNTIME.tasks().find(|task| task.id == id).unwrap().priority
+}
+
+/// Puts the previous hardware abstraction back when dropped, even if the test fails, so that other tests can sleep
+pub(crate) struct Restore(&'static dyn hal::Hal);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        unsafe { hal::set_hal(self.0) };
+    }
+}
+
+/// Makes the runtime use a mock, such as for its clock, until the returned value is dropped
+pub(crate) fn use_mock(mock: &'static Mock) -> Restore {
+    Restore(unsafe { hal::set_hal(mock) })
+}
+
+/// Gets the state of a thread
+fn state_of<T>(handle: &JoinHandle<T>) -> ThreadState {
+    RUNTIME.tasks().find(|task| task.id == handle.id()).unwrap().state
+}
+
+#[test]
+fn spawn_and_join() {
+    let _serial = serial();
+
+    let handle = spawn(|| 6 * 7);
+
+    assert_eq!(handle.join(), Some(42));
+}
+
+#[test]
+fn spawned_thread_waits_until_yield() {
+    let _serial = serial();
+
+    let ran = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let ran = ran.clone();
+        spawn(move || ran.store(true, Ordering::SeqCst))
+    };
+
+    assert!(!ran.load(Ordering::SeqCst));
+    handle.join();
+    assert!(ran.load(Ordering::SeqCst));
+}
+
+#[test]
+fn yield_next_round_robins_equal_priorities() {
+    let _serial = serial();
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = ["a", "b"].into_iter().map(|name| {
+        let log = log.clone();
+        spawn(move || {
+            for i in 0..3 {
+                log.lock().unwrap().push((name, i));
+                RUNTIME.yield_next();
+            }
+        })
+    }).collect();
+
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(*log.lock().unwrap(), vec![("a", 0), ("b", 0), ("a", 1), ("b", 1), ("a", 2), ("b", 2)]);
+}
+
+#[test]
+fn higher_priority_runs_first() {
+    let _serial = serial();
+
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = [(1, "low"), (9, "high"), (5, "middle")].into_iter().map(|(priority, name)| {
+        let log = log.clone();
+        ThreadBuilder::new().stack_size(TEST_STACK_SIZE).priority(priority)
+            .spawn(move || log.lock().unwrap().push(name))
+            .unwrap()
+    }).collect();
+
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(*log.lock().unwrap(), vec!["high", "middle", "low"]);
+}
+
+#[test]
+fn await_wake_sleeps_until_woken() {
+    let _serial = serial();
+
+    let signal = WakeupSignal::MutexRelease(0x1234);
+    let woken = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let woken = woken.clone();
+        spawn(move || {
+            RUNTIME.await_wake(signal);
+            woken.store(true, Ordering::SeqCst);
+        })
+    };
+
+    // Let it run until it waits for the signal
+    RUNTIME.yield_next();
+    assert_eq!(state_of(&handle), ThreadState::AwaitWake(signal));
+    assert!(!woken.load(Ordering::SeqCst));
+
+    // Yielding again does not wake it
+    RUNTIME.yield_next();
+    assert!(!woken.load(Ordering::SeqCst));
+
+    // Waking switches to it straight away, as it has the same priority
+    assert!(RUNTIME.wake(handle.id(), signal));
+    assert!(woken.load(Ordering::SeqCst));
+
+    handle.join();
+}
+
+#[test]
+fn wake_needs_the_right_signal() {
+    let _serial = serial();
+
+    let signal = WakeupSignal::MutexRelease(0x1234);
+    let handle = spawn(move || RUNTIME.await_wake(signal));
+
+    RUNTIME.yield_next();
+
+    assert!(!RUNTIME.wake(handle.id(), WakeupSignal::MutexRelease(0x5678)));
+    assert_eq!(state_of(&handle), ThreadState::AwaitWake(signal));
+
+    assert!(RUNTIME.wake(handle.id(), signal));
+    assert!(!RUNTIME.wake(handle.id(), signal));
+
+    handle.join();
+}
+
+#[test]
+fn wake_does_not_switch_to_lower_priority() {
+    let _serial = serial();
+
+    let signal = WakeupSignal::MutexRelease(0x1234);
+    let sent = Arc::new(AtomicBool::new(false));
+    let woken = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let (sent, woken) = (sent.clone(), woken.clone());
+        ThreadBuilder::new().stack_size(TEST_STACK_SIZE).priority(1)
+            .spawn(move || {
+                // Waiting returns straight away while nothing else can run, so wait until the signal was sent
+                while !sent.load(Ordering::SeqCst) {
+                    RUNTIME.await_wake(signal);
+                }
+                woken.store(true, Ordering::SeqCst);
+            })
+            .unwrap()
+    };
+
+    // Yielding keeps running this thread, so sleep to let the lower priority thread wait for the signal
+    while state_of(&handle) != ThreadState::AwaitWake(signal) {
+        RUNTIME.sleep_ms(1);
+    }
+
+    sent.store(true, Ordering::SeqCst);
+    assert!(RUNTIME.wake(handle.id(), signal));
+    assert_eq!(state_of(&handle), ThreadState::Ready);
+    assert!(!woken.load(Ordering::SeqCst));
+
+    handle.join();
+    assert!(woken.load(Ordering::SeqCst));
+}
+
+#[test]
+fn sleeping_thread_wakes_at_its_deadline() {
+    let _serial = serial();
+    static MOCK: Mock = Mock::new();
+    let _restore = use_mock(&MOCK);
+
+    let woken = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let woken = woken.clone();
+        spawn(move || {
+            RUNTIME.sleep_ms(10);
+            woken.store(true, Ordering::SeqCst);
+        })
+    };
+
+    RUNTIME.yield_next();
+    assert_eq!(state_of(&handle), ThreadState::AwaitTime(MOCK.time_ms() + 10));
+
+    MOCK.advance_ms(9);
+    RUNTIME.yield_next();
+    assert!(!woken.load(Ordering::SeqCst));
+
+    MOCK.advance_ms(1);
+    RUNTIME.yield_next();
+    assert!(woken.load(Ordering::SeqCst));
+
+    handle.join();
+}
+
+#[test]
+fn kill_stops_a_thread() {
+    let _serial = serial();
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let handle = {
+        let count = count.clone();
+        spawn(move || loop {
+            count.fetch_add(1, Ordering::SeqCst);
+            RUNTIME.yield_next();
+        })
+    };
+
+    RUNTIME.yield_next();
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    assert!(RUNTIME.kill(handle.id()));
+    assert!(RUNTIME.tasks().all(|task| task.id != handle.id()));
+
+    // It never runs again, and there is nothing left to kill
+    RUNTIME.yield_next();
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert!(!RUNTIME.kill(handle.id()));
+    assert!(!RUNTIME.kill(super::KERNEL_THREAD));
+
+    assert_eq!(handle.join(), None);
+}
+
+#[test]
+fn killing_a_thread_before_it_starts_drops_its_closure() {
+    let _serial = serial();
+
+    let captured = Arc::new(());
+    let handle = {
+        let captured = captured.clone();
+        spawn(move || drop(captured))
+    };
+
+    assert_eq!(Arc::strong_count(&captured), 2);
+    assert!(RUNTIME.kill(handle.id()));
+    assert_eq!(Arc::strong_count(&captured), 1);
+}
+
+#[test]
+fn suspended_thread_does_not_run_until_resumed() {
+    let _serial = serial();
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let (count, stop) = (count.clone(), stop.clone());
+        spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                count.fetch_add(1, Ordering::SeqCst);
+                RUNTIME.yield_next();
+            }
+        })
+    };
+
+    RUNTIME.yield_next();
+    assert!(RUNTIME.suspend(handle.id()));
+    assert!(!RUNTIME.suspend(handle.id()));
+    assert_eq!(state_of(&handle), ThreadState::Suspended);
+
+    let before = count.load(Ordering::SeqCst);
+    RUNTIME.yield_next();
+    RUNTIME.yield_next();
+    assert_eq!(count.load(Ordering::SeqCst), before);
+
+    // Resuming puts it back as it was, so it picks up where it left off
+    assert!(RUNTIME.resume(handle.id()));
+    assert!(!RUNTIME.resume(handle.id()));
+    assert_eq!(state_of(&handle), ThreadState::Ready);
+    RUNTIME.yield_next();
+    assert_eq!(count.load(Ordering::SeqCst), before + 1);
+
+    stop.store(true, Ordering::SeqCst);
+    handle.join();
+}
+
+#[test]
+fn woken_thread_stays_suspended() {
+    let _serial = serial();
+
+    let signal = WakeupSignal::MutexRelease(0x1234);
+    let handle = spawn(move || RUNTIME.await_wake(signal));
+
+    RUNTIME.yield_next();
+    assert!(RUNTIME.suspend(handle.id()));
+
+    // The wake is not lost, but the thread does not run until it is resumed
+    assert!(RUNTIME.wake(handle.id(), signal));
+    assert_eq!(state_of(&handle), ThreadState::Suspended);
+
+    assert!(RUNTIME.resume(handle.id()));
+    assert_eq!(state_of(&handle), ThreadState::Ready);
+
+    handle.join();
+}
+
+#[test]
+fn thread_can_suspend_itself() {
+    let _serial = serial();
+
+    let resumed = Arc::new(AtomicBool::new(false));
+    let handle = {
+        let resumed = resumed.clone();
+        spawn(move || {
+            RUNTIME.suspend(RUNTIME.current_task());
+            resumed.store(true, Ordering::SeqCst);
+        })
+    };
+
+    RUNTIME.yield_next();
+    assert_eq!(state_of(&handle), ThreadState::Suspended);
+    assert!(!resumed.load(Ordering::SeqCst));
+
+    assert!(RUNTIME.resume(handle.id()));
+    handle.join();
+    assert!(resumed.load(Ordering::SeqCst));
+}
+
+/// Counts how many times a value is dropped
+struct CountsDrops(&'static AtomicUsize);
+
+impl Drop for CountsDrops {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn thread_local_is_created_on_first_use() {
+    let _serial = serial();
+
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+    crate::thread_local! {
+        static VALUE: u32 = {
+            CREATED.fetch_add(1, Ordering::SeqCst);
+            7
+        };
+    }
+
+    let handle = spawn(|| {
+        // Nothing is created until the thread uses the key
+        RUNTIME.yield_next();
+        let before = CREATED.load(Ordering::SeqCst);
+
+        let value = VALUE.with(|value| *value) + VALUE.with(|value| *value);
+        (before, CREATED.load(Ordering::SeqCst), value)
+    });
+
+    assert_eq!(handle.join(), Some((0, 1, 14)));
+}
+
+#[test]
+fn each_thread_has_its_own_thread_local() {
+    let _serial = serial();
+
+    crate::thread_local! {
+        static COUNTER: core::cell::Cell<u32> = core::cell::Cell::new(0);
+    }
+
+    // The threads take turns, so each sees the other's increments if the value is shared
+    let handles: Vec<_> = (1..=2).map(|increments| {
+        spawn(move || {
+            for _ in 0..increments {
+                COUNTER.with(|counter| counter.set(counter.get() + 1));
+                RUNTIME.yield_next();
+            }
+            COUNTER.with(|counter| counter.get())
+        })
+    }).collect();
+
+    let counts: Vec<_> = handles.into_iter().map(|handle| handle.join()).collect();
+    assert_eq!(counts, vec![Some(1), Some(2)]);
+}
+
+#[test]
+fn thread_local_is_dropped_when_the_thread_finishes() {
+    let _serial = serial();
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    crate::thread_local! {
+        static VALUE: CountsDrops = CountsDrops(&DROPPED);
+    }
+
+    // A thread that never uses the key has nothing to drop
+    spawn(|| ()).join();
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 0);
+
+    spawn(|| VALUE.with(|_| ())).join();
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn thread_local_is_dropped_when_the_thread_is_killed() {
+    let _serial = serial();
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    crate::thread_local! {
+        static VALUE: CountsDrops = CountsDrops(&DROPPED);
+    }
+
+    let handle = spawn(|| {
+        VALUE.with(|_| ());
+        loop {
+            RUNTIME.yield_next();
+        }
+    });
+
+    RUNTIME.yield_next();
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 0);
+
+    assert!(RUNTIME.kill(handle.id()));
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn killed_threads_locals_are_dropped_before_its_locks_are_handed_on() {
+    let _serial = serial();
+
+    static MUTEX: crate::sync::mutex::Mutex<()> = crate::sync::mutex::Mutex::new(());
+    static SAW_LOCK_HELD: AtomicBool = AtomicBool::new(false);
+
+    /// Records whether the mutex is still held when it is dropped
+    struct ChecksLock;
+
+    impl Drop for ChecksLock {
+        fn drop(&mut self) {
+            SAW_LOCK_HELD.store(MUTEX.is_taken(), Ordering::SeqCst);
+        }
+    }
+
+    crate::thread_local! {
+        static VALUE: ChecksLock = ChecksLock;
+    }
+
+    let handle = spawn(|| {
+        let _guard = MUTEX.acquire();
+        VALUE.with(|_| ());
+        loop {
+            RUNTIME.yield_next();
+        }
+    });
+
+    RUNTIME.yield_next();
+    assert!(MUTEX.is_taken());
+
+    // The thread still holds the lock while its locals are dropped, and gives it up after
+    assert!(RUNTIME.kill(handle.id()));
+    assert!(SAW_LOCK_HELD.load(Ordering::SeqCst));
+    assert!(!MUTEX.is_taken());
+}
+
+#[test]
+fn changing_the_competition_mode_restarts_the_user_thread() {
+    let _serial = serial();
+    static MOCK: Mock = Mock::new();
+    let _restore = use_mock(&MOCK);
+
+    /// The modes the user thread has been started in
+    static STARTED: Mutex<Vec<CompetitionMode>> = Mutex::new(Vec::new());
+
+    fn user(mode: CompetitionMode) {
+        STARTED.lock().unwrap().push(mode);
+        loop {
+            RUNTIME.yield_next();
+        }
+    }
+    static USER: fn(CompetitionMode) = user;
+
+    let generation = || unsafe { RUNTIME.threads() }[USER_THREAD.0].generation;
+
+    // The mock starts in driver control
+    let mode = RUNTIME.poll_mode(&USER, CompetitionMode::Disabled);
+    assert_eq!(mode, CompetitionMode::Driver);
+    RUNTIME.yield_next();
+    let first = generation();
+
+    // Nothing changes while the mode stays the same
+    assert_eq!(RUNTIME.poll_mode(&USER, mode), CompetitionMode::Driver);
+    RUNTIME.yield_next();
+    assert_eq!(generation(), first);
+
+    // The running user thread is killed and a new one started in the new mode
+    MOCK.set_competition_mode(CompetitionMode::Autonomous);
+    assert_eq!(RUNTIME.poll_mode(&USER, mode), CompetitionMode::Autonomous);
+    assert!(!RUNTIME.is_alive(USER_THREAD, first));
+    assert!(RUNTIME.is_alive(USER_THREAD, generation()));
+    RUNTIME.yield_next();
+
+    assert_eq!(*STARTED.lock().unwrap(), vec![CompetitionMode::Driver, CompetitionMode::Autonomous]);
+    assert!(RUNTIME.kill(USER_THREAD));
+}
+
+#[test]
+fn thread_list_grows_and_reuses_slots_under_a_new_generation() {
+    let _serial = serial();
+
+    // Keep more threads alive at once than the list has ever held, so that it has to grow
+    let count = unsafe { RUNTIME.threads() }.len().max(super::INITIAL_THREADS) + 4;
+    let release = Arc::new(AtomicBool::new(false));
+    let handles: Vec<_> = (0..count).map(|i| {
+        let release = release.clone();
+        spawn(move || {
+            while !release.load(Ordering::SeqCst) {
+                RUNTIME.yield_next();
+            }
+            i
+        })
+    }).collect();
+    assert!(unsafe { RUNTIME.threads() }.len() > super::INITIAL_THREADS);
+    RUNTIME.yield_next();
+
+    let stale: Vec<Task> = handles.iter()
+        .map(|handle| Task { id: handle.id(), generation: unsafe { RUNTIME.threads() }[handle.id().0].generation })
+        .collect();
+    release.store(true, Ordering::SeqCst);
+    for (i, handle) in handles.into_iter().enumerate() {
+        assert_eq!(handle.join(), Some(i));
+    }
+
+    // A new thread takes one of the freed slots, which the old tasks no longer match
+    let handle = spawn(|| RUNTIME.current());
+    let old = *stale.iter().find(|task| task.id == handle.id()).unwrap();
+    assert!(!RUNTIME.is_alive(old.id, old.generation));
+    assert!(unsafe { RUNTIME.thread_of(old) }.is_none());
+
+    let new = handle.join().unwrap();
+    assert_eq!(new.id, old.id);
+    assert_ne!(new, old);
+}
+
+#[test]
+fn high_water_mark_rises_with_deep_recursion() {
+    let _serial = serial();
+
+    /// Uses about a kilobyte of stack for every level
+    fn recurse(depth: usize) -> u8 {
+        let buffer = core::hint::black_box([depth as u8; 1024]);
+        if depth == 0 {
+            buffer[0]
+        } else {
+            recurse(depth - 1).wrapping_add(buffer[1023])
+        }
+    }
+
+    let handle = spawn(|| {
+        let before = RUNTIME.stack_high_water_mark(RUNTIME.current_task()).unwrap();
+        recurse(16);
+        let after = RUNTIME.stack_high_water_mark(RUNTIME.current_task()).unwrap();
+        (before, after)
+    });
+    let id = handle.id();
+    let (before, after) = handle.join().unwrap();
+
+    assert!(after >= before + 16 * 1024, "{} then {}", before, after);
+    assert!(after < TEST_STACK_SIZE);
+    assert_eq!(RUNTIME.stack_high_water_mark(id), None);
+}
@@ -1,23 +1,133 @@
 // Contains the implementation of a thread
 
-use core::intrinsics::size_of;
+use core::fmt;
+use core::mem::size_of;
+
+use core::any::Any;
 
 use alloc::vec::{Vec};
-use alloc::vec;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use super::SpawnError;
+use super::stats::ThreadStats;
+use super::local::LocalValue;
+use super::resource::ResourceRef;
+
+/// The size of a thread's stack when none is given
+pub const DEFAULT_STACK_SIZE: usize = 0x1000; // 4 KiB for now should be plenty.
+
+/// The smallest stack a thread can be given. Anything smaller is rounded up to this.
+#[cfg(not(feature = "preemptive"))]
+pub const MIN_STACK_SIZE: usize = 0x100;
+
+/// The smallest stack a thread can be given. Anything smaller is rounded up to this.
+/// Preempted threads also keep their floating point registers on their stack.
+#[cfg(feature = "preemptive")]
+pub const MIN_STACK_SIZE: usize = 0x200;
+
+/// The alignment of the top of every stack, as required by the ARM procedure call standard
+#[cfg(target_arch = "arm")]
+const STACK_ALIGN: usize = 8;
+
+/// The alignment of the top of every stack, as required by the System V x86_64 ABI
+#[cfg(target_arch = "x86_64")]
+const STACK_ALIGN: usize = 16;
+
+/// The byte every new stack is filled with, so that untouched parts of the stack can be found
+const CANARY_BYTE: u8 = 0xA5;
+
+/// The number of words a suspended thread's context takes up on its stack:
+/// r0 to r12, the link register and the program counter
+#[cfg(all(target_arch = "arm", not(feature = "preemptive")))]
+const FRAME_WORDS: usize = 15;
+
+/// The number of words a suspended thread's context takes up on its stack: the floating point status
+/// register, d0 to d31, r0 to r12, the link register, the program counter and the program status register.
+/// The status and floating point registers are needed to resume threads that were preempted by the tick,
+/// as the tick can interrupt libv5rt partway through a calculation.
+#[cfg(all(target_arch = "arm", feature = "preemptive"))]
+const FRAME_WORDS: usize = 81;
+
+/// The number of words at the start of a frame taken up by the floating point status register and d0 to d31
+#[cfg(all(target_arch = "arm", feature = "preemptive"))]
+const FLOAT_WORDS: usize = 65;
+
+/// The number of words at the start of a frame taken up by the floating point registers, which are not saved
+#[cfg(all(target_arch = "arm", not(feature = "preemptive")))]
+const FLOAT_WORDS: usize = 0;
+
+/// The number of words a new thread's context takes up on its stack: r15 to r12, rbx, rbp and
+/// the return address, followed by the guard as the start function's own return address
+#[cfg(target_arch = "x86_64")]
+const FRAME_WORDS: usize = 8;
+
+/// The word in a new thread's frame that is loaded into the program counter
+#[cfg(target_arch = "arm")]
+const ENTRY_WORD: usize = FLOAT_WORDS + 14;
 
-/// The size of a thread's stack
-pub const STACK_SIZE: usize = 0x1000; // 4 KiB for now should be plenty.
+/// The word in a new thread's frame that the start function returns to
+#[cfg(target_arch = "arm")]
+const GUARD_WORD: usize = FLOAT_WORDS + 13;
+
+/// The word in a new thread's frame that is loaded into the program status register
+#[cfg(all(target_arch = "arm", feature = "preemptive"))]
+const STATUS_WORD: usize = FLOAT_WORDS + 15;
+
+/// The word in a new thread's frame that is loaded into the program counter
+#[cfg(target_arch = "x86_64")]
+const ENTRY_WORD: usize = 6;
+
+/// The word in a new thread's frame that the start function returns to
+#[cfg(target_arch = "x86_64")]
+const GUARD_WORD: usize = 7;
+
+/// The number of bytes at the bottom of the stack that must never be written to.
+/// If any of them change, the thread has overflowed its stack.
+const CANARY_SIZE: usize = 16;
+
+/// The priority given to threads that do not ask for one. Higher values run first.
+pub const DEFAULT_PRIORITY: u8 = 8;
+
+/// The identifier of a thread, which is its index in the runtime's thread list
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ThreadId(pub(crate) usize);
+
+impl ThreadId {
+    /// Gets the index of the thread in the runtime's thread list
+    pub fn as_usize(&self) -> usize {
+        self.0
+    }
+}
+
+/// A thread together with the generation of its slot, which tells it apart from any thread that reuses the slot later
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct Task {
+    /// The slot the thread runs in
+    pub(crate) id: ThreadId,
+    /// The generation of the slot when the thread was spawned
+    pub(crate) generation: u32,
+}
 
 /// A wakeup signal
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum WakeupSignal {
-    /// The task is waiting on a mutex
-    MutexRelease,
+    /// The task is waiting on the mutex at this address
+    MutexRelease(usize),
+    /// The task is waiting on the read-write lock at this address
+    RwLockRelease(usize),
+    /// The task is waiting for a permit from the semaphore at this address
+    SemaphoreRelease(usize),
+    /// The task is waiting for the event at this address to be set
+    EventSet(usize),
+    /// The task is waiting for a thread to finish
+    Join(ThreadId),
 }
 
 
 /// The state of a thread
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ThreadState {
     /// A task is available to be assigned
     Available,
@@ -27,56 +137,204 @@ pub enum ThreadState {
     Running,
     /// The task is waiting for a wakeup signal
     AwaitWake(WakeupSignal),
-    /// The task is waiting for a specific time
+    /// The task is sleeping until the system time (in milliseconds) reaches this deadline
     AwaitTime(u32),
+    /// The task is waiting for a wakeup signal, but gives up once the system time (in milliseconds) reaches the deadline
+    AwaitWakeUntil(WakeupSignal, u32),
+    /// The task has returned from its entry point and is waiting for its stack to be freed
+    Finished,
+    /// The task will not be scheduled until it is resumed
+    Suspended,
+}
 
+impl fmt::Display for WakeupSignal {
+    /// Writes the signal like its `Debug` output, but with addresses in hexadecimal
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WakeupSignal::MutexRelease(address) => write!(f, "MutexRelease({:#x})", address),
+            WakeupSignal::RwLockRelease(address) => write!(f, "RwLockRelease({:#x})", address),
+            WakeupSignal::SemaphoreRelease(address) => write!(f, "SemaphoreRelease({:#x})", address),
+            WakeupSignal::EventSet(address) => write!(f, "EventSet({:#x})", address),
+            WakeupSignal::Join(id) => write!(f, "Join({})", id.0),
+        }
+    }
 }
 
+impl fmt::Display for ThreadState {
+    /// Writes the state like its `Debug` output, but with addresses in hexadecimal.
+    /// Padding is applied to the whole state.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            ThreadState::AwaitWake(signal) => alloc::format!("AwaitWake({})", signal),
+            ThreadState::AwaitWakeUntil(signal, deadline) => alloc::format!("AwaitWakeUntil({}, {})", signal, deadline),
+            state => alloc::format!("{:?}", state),
+        };
 
+        f.pad(&state)
+    }
+}
 
 /// A thread that contains the utilities for switching between contexts
 /// The thread struct should *never* be put into any relocatable data structure such as a 
-/// Vec.
-#[derive(Clone)]
+/// Vec directly. The runtime boxes every thread so that growing its list does not move them.
 pub struct Thread {
-    /// The contents of the stack
+    /// The contents of the stack. This is empty until the thread is spawned.
     stack: Vec<u8>,
     /// The current offset in the stack
     stack_offset: usize,
     /// The current thread state
     pub state: ThreadState,
+    /// The state a suspended thread goes back to when it is resumed
+    resume_state: ThreadState,
+    /// Counts how many times this slot has been given a new thread, so that a handle
+    /// to an old thread can tell it apart from whatever reused the slot
+    pub(crate) generation: u32,
+    /// The scheduling priority of the thread. Higher values run first.
+    /// This is raised above the base priority while a higher priority thread waits on a lock it holds.
+    pub priority: u8,
+    /// The priority the thread was given, which it goes back to once no higher priority thread waits on a lock it holds
+    pub(crate) base_priority: u8,
+    /// The locks the thread holds, once for every time it took them, so that they are given up if it is killed
+    pub(crate) held: Vec<ResourceRef>,
+    /// The queue the thread is waiting in, so that it is taken out if it is killed
+    pub(crate) waiting_in: Option<ResourceRef>,
+    /// The closure to run when the thread first starts
+    pub(crate) entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The packet the thread's result goes into. The thread holds it here rather than in its entry point,
+    /// so that it is freed along with the thread even if the thread is killed.
+    pub(crate) packet: Option<Arc<dyn Any + Send + Sync>>,
+    /// The name of the thread, if it was given one
+    pub name: Option<String>,
+    /// How much the thread has run
+    pub(crate) stats: ThreadStats,
+    /// The thread's thread-local values, indexed by their key's storage slot
+    pub(crate) locals: Vec<Option<LocalValue>>,
 }
 
 impl Thread {
 
-    /// Creates a new empty thread
+    /// Creates a new empty thread. No stack is allocated until the thread is initialized.
     pub fn new() -> Thread {
-        Thread { stack: vec![0u8; STACK_SIZE], stack_offset: 0, state: ThreadState::Available }
+        Thread { stack: Vec::new(), stack_offset: 0, state: ThreadState::Available, resume_state: ThreadState::Available, generation: 0, priority: DEFAULT_PRIORITY, base_priority: DEFAULT_PRIORITY, held: Vec::new(), waiting_in: None, entry: None, packet: None, name: None, stats: ThreadStats::default(), locals: Vec::new() }
     }
 
     /// Initializes the thread to be ready
-    pub fn initialize(&mut self, entry: fn()) {
-        // Initialize a new stack
-        self.stack = vec![0u8; STACK_SIZE];
+    pub fn initialize(&mut self, entry: Box<dyn FnOnce() + Send>, priority: u8, stack_size: usize, name: Option<String>) -> Result<(), SpawnError> {
+        // Make sure the stack can hold more than just the initial frame, and keep its size aligned
+        let stack_size = stack_size.max(MIN_STACK_SIZE).next_multiple_of(STACK_ALIGN);
 
-        
+        // Initialize a new stack, reporting failure instead of hitting the allocation error handler
+        let mut stack = Vec::new();
+        stack.try_reserve_exact(stack_size).map_err(|_| SpawnError::StackAllocationFailed)?;
+        stack.resize(stack_size, CANARY_BYTE);
+        self.stack = stack;
+
+        // Store the entry point for the start function to pick up
+        self.entry = Some(entry);
+        self.name = name;
+        self.stats = ThreadStats::default();
 
-        // Get the stack top
-        let top = (core::ptr::addr_of!(self.stack) as usize + self.stack.len()) as *mut usize;
+        // Get the start of the initial frame below the stack top.
+        // On ARM it is laid out as r0 to r12, lr, pc and, when preemptive, the status register,
+        // with the floating point status register and d0 to d31 below them.
+        let frame = (self.stack_end() as *mut usize).wrapping_sub(FRAME_WORDS);
 
         unsafe {
-            // Push pc as the entry point
-            core::ptr::write(top.offset(-1), entry as usize);
+            // Start the thread with the floating point registers and status cleared
+            #[cfg(feature = "preemptive")]
+            core::ptr::write_bytes(frame, 0, FLOAT_WORDS);
+
+            // Push pc as the start function, which runs the entry point
+            core::ptr::write(frame.add(ENTRY_WORD), super::internal::thread_start as *const () as usize);
 
             // Push the guard function to prevent us from returning to null
-            core::ptr::write(top.offset(-2), super::internal::guard as usize);
+            core::ptr::write(frame.add(GUARD_WORD), super::internal::guard as *const () as usize);
+
+            // Start the thread with interrupts enabled so that it can be preempted
+            #[cfg(feature = "preemptive")]
+            core::ptr::write(frame.add(STATUS_WORD), super::internal::initial_status());
         }
         
-        // Set our default offset to 15 usizes from the top (14 registers, one indexed)
-        self.stack_offset = 15;
+        // Set our default offset to the size of the frame
+        self.stack_offset = FRAME_WORDS;
 
-        // Set our state to ready
+        // Set our priority and state to ready
+        self.priority = priority;
+        self.base_priority = priority;
+        self.held = Vec::new();
+        self.waiting_in = None;
         self.state = ThreadState::Ready;
+        self.generation = self.generation.wrapping_add(1);
+
+        Ok(())
+    }
+
+    /// Moves the thread from waiting on a signal to ready, returning false if it was not waiting on it.
+    /// A suspended thread that was waiting on the signal stays suspended, but is ready once resumed.
+    pub fn wake(&mut self, signal: WakeupSignal) -> bool {
+        let waiting = |state| match state {
+            ThreadState::AwaitWake(awaited) | ThreadState::AwaitWakeUntil(awaited, _) => awaited == signal,
+            _ => false,
+        };
+
+        if waiting(self.state) {
+            self.state = ThreadState::Ready;
+            true
+        } else if self.state == ThreadState::Suspended && waiting(self.resume_state) {
+            self.resume_state = ThreadState::Ready;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Suspends the thread, remembering the state to resume it in
+    pub fn suspend(&mut self, state: ThreadState) {
+        self.resume_state = state;
+        self.state = ThreadState::Suspended;
+    }
+
+    /// Puts a suspended thread back into the state it was suspended in
+    pub fn resume(&mut self) {
+        self.state = self.resume_state;
+    }
+
+    /// Frees the thread's stack and makes it available to be assigned again.
+    /// This must never be called on the running thread.
+    pub fn release(&mut self) {
+        self.stack = Vec::new();
+        self.stack_offset = 0;
+        self.entry = None;
+        self.packet = None;
+        self.name = None;
+        self.locals = Vec::new();
+        self.held = Vec::new();
+        self.waiting_in = None;
+        self.state = ThreadState::Available;
+    }
+
+    /// Gets the size of the thread's stack in bytes
+    pub fn stack_size(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns false if the canary at the bottom of the stack has been overwritten,
+    /// meaning the thread has overflowed its stack. Threads without a stack of their own always pass.
+    pub fn check_canary(&self) -> bool {
+        self.stack.iter().take(CANARY_SIZE).all(|b| *b == CANARY_BYTE)
+    }
+
+    /// Gets the largest number of bytes of stack the thread has used so far, found by
+    /// counting the untouched canary bytes from the bottom of the stack.
+    pub fn stack_high_water_mark(&self) -> usize {
+        let untouched = self.stack.iter().take_while(|b| **b == CANARY_BYTE).count();
+        self.stack.len() - untouched
+    }
+
+    /// Gets the address of the top of the stack, which is the end of the stack's buffer
+    /// rounded down to the required alignment.
+    fn stack_end(&self) -> usize {
+        (self.stack.as_ptr() as usize + self.stack.len()) & !(STACK_ALIGN - 1)
     }
 
     /// Gets the stack pointer of this thread
@@ -84,12 +342,20 @@ impl Thread {
         // Add the size of the stack to the address of the stack to get the end of the stack,
         // and subtract the offset to get the stack pointer of this thread. We do this to assist
         // in the case of this struct getting relocated while the thread is suspended so that we do not have to reset the stack pointer each time.
-        core::ptr::addr_of!(self.stack) as usize + self.stack.len() - self.stack_offset * size_of::<usize>()
+        // The OS thread runs on the system stack rather than its own, so this may wrap around.
+        self.stack_end().wrapping_sub(self.stack_offset * size_of::<usize>())
+    }
+
+    /// Saves the stack pointer of a thread whose context was pushed by the tick interrupt
+    #[cfg(feature = "preemptive")]
+    pub fn set_sp(&mut self, sp: usize) {
+        self.stack_offset = self.stack_end().wrapping_sub(sp) / size_of::<usize>();
     }
 
     /// Switches contexts from a different thread to the stack pointer of a different thread
     /// # Safety
     /// This function assumes the stack pointer is correct.
+    #[cfg(all(target_arch = "arm", not(feature = "preemptive")))]
     pub unsafe fn switch_from(&self, to: usize) {
         // This function will return to the new context.
         // The way this works follows:
@@ -104,7 +370,7 @@ impl Thread {
         let so_addr = core::ptr::addr_of!(self.stack_offset);
 
         // Get the end of the current stack
-        let stack_end = core::ptr::addr_of!(self.stack) as usize + self.stack.len();
+        let stack_end = self.stack_end();
         
         
         core::arch::asm!(
@@ -121,18 +387,145 @@ impl Thread {
             "pop {{lr}}", // Pop the link register
             "pop {{pc}}", // Pop the program counter, finishing up the context switch
             "2:",
-            in(reg) super::internal::guard as usize, // Store the stack guard inj a register
+            in(reg) super::internal::guard as *const () as usize, // Store the stack guard inj a register
             out(reg) _, // A scratch register to use
             in(reg) so_addr, // Store the address of the stack pointer variable in a register
             in(reg) to, // The stack pointer of the new thread
-            in(reg) stack_end, // The current stack end address
+            inout(reg) stack_end => _, // The current stack end address, which is overwritten with the offset
         );
         
     }
+
+    /// Switches contexts from a different thread to the stack pointer of a different thread.
+    /// This saves the same frame as the tick interrupt does, including the program status register
+    /// and the floating point registers, so that threads suspended either way can be resumed either way.
+    /// # Safety
+    /// This function assumes the stack pointer is correct.
+    #[cfg(all(target_arch = "arm", feature = "preemptive"))]
+    pub unsafe fn switch_from(&self, to: usize) {
+        // Get the address of the stack offset variable
+        let so_addr = core::ptr::addr_of!(self.stack_offset);
+
+        // Get the end of the current stack
+        let stack_end = self.stack_end();
+
+        core::arch::asm!(
+            "/*{0}*/",
+            "mrs {1}, cpsr", // Read the program status register
+            "push {{{1}}}", // Push it so that the thread resumes with the same status
+            "ldr {1}, =2f", // Load the label 2 into the scratch register (this is where we want to jump to when our thread resumes execution)
+            "push {{{1}}}", // Push the end label as the saved program counter
+            "push {{lr}}", // Push the link register (this should be overwritten when a function returns)
+            "push {{r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, r11, r12}}", // Push the general purpose registers
+            "vpush {{d16-d31}}", // Push the floating point registers
+            "vpush {{d0-d15}}",
+            "vmrs {1}, fpscr", // Push the floating point status register
+            "push {{{1}}}",
+            "sub {4}, sp", // Convert the current stack pointer to an offset 
+            "lsr {4}, 2", // Divide the offset by four in order to get the offset in usizes.
+            "str {4}, [{2}]", // Save the stack offset
+            "mov sp, {3}", // Load the new stack pointer
+            "pop {{{1}}}", // Pop the floating point status register
+            "vmsr fpscr, {1}",
+            "vpop {{d0-d15}}", // Pop the floating point registers
+            "vpop {{d16-d31}}",
+            "pop {{r0, r1, r2, r3, r4, r5, r6, r7, r8, r9, r10, r11, r12}}", // Pop the general purpose registers
+            "pop {{lr}}", // Pop the link register
+            "rfeia sp!", // Pop the program counter and status register, finishing up the context switch
+            "2:",
+            in(reg) super::internal::guard as *const () as usize, // Store the stack guard in a register
+            out(reg) _, // A scratch register to use
+            in(reg) so_addr, // Store the address of the stack pointer variable in a register
+            in(reg) to, // The stack pointer of the new thread
+            inout(reg) stack_end => _, // The current stack end address, which is overwritten with the offset
+        );
+    }
+
+    /// Switches contexts from a different thread to the stack pointer of a different thread.
+    /// This is the host version used to test the runtime, which only saves the registers
+    /// the System V x86_64 ABI requires a function call to preserve.
+    /// # Safety
+    /// This function assumes the stack pointer is correct.
+    #[cfg(target_arch = "x86_64")]
+    pub unsafe fn switch_from(&self, to: usize) {
+        // Get the address of the stack offset variable
+        let so_addr = core::ptr::addr_of!(self.stack_offset);
+
+        // Get the end of the current stack
+        let stack_end = self.stack_end();
+
+        core::arch::asm!(
+            "lea rax, [rip + 2f]", // Load the label 2 into the scratch register (this is where we want to jump to when our thread resumes execution)
+            "sub rsp, 128", // Step over the red zone, which the compiler may be keeping values in
+            "push rax", // Push the end label as the return address
+            "push rbp", // Push the callee saved registers
+            "push rbx",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "sub rdx, rsp", // Convert the current stack pointer to an offset
+            "shr rdx, 3", // Divide the offset by eight in order to get the offset in usizes.
+            "mov [rdi], rdx", // Save the stack offset
+            "mov rsp, rsi", // Load the new stack pointer
+            "pop r15", // Pop the callee saved registers
+            "pop r14",
+            "pop r13",
+            "pop r12",
+            "pop rbx",
+            "pop rbp",
+            "ret", // Pop the return address, finishing up the context switch
+            "2:",
+            "add rsp, 128", // Step back over the red zone
+            out("rax") _, // A scratch register to use
+            in("rdi") so_addr, // Store the address of the stack pointer variable in a register
+            in("rsi") to, // The stack pointer of the new thread
+            inout("rdx") stack_end => _, // The current stack end address, which is overwritten with the offset
+            clobber_abi("C"), // Everything else may be changed by the thread we switch to
+        );
+    }
 }
 
+
 impl Default for Thread {
     fn default() -> Thread {
         Thread::new()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::{Thread, CANARY_BYTE, CANARY_SIZE, DEFAULT_PRIORITY, DEFAULT_STACK_SIZE};
+
+    #[test]
+    fn new_stack_keeps_its_canary() {
+        let mut thread = Thread::new();
+        thread.initialize(Box::new(|| {}), DEFAULT_PRIORITY, DEFAULT_STACK_SIZE, None).unwrap();
+
+        assert!(thread.check_canary());
+    }
+
+    #[test]
+    fn overwritten_canary_is_detected() {
+        let mut thread = Thread::new();
+        thread.initialize(Box::new(|| {}), DEFAULT_PRIORITY, DEFAULT_STACK_SIZE, None).unwrap();
+
+        // Only the topmost canary byte is hit, as by a stack just barely overflowing
+        thread.stack[CANARY_SIZE - 1] = !CANARY_BYTE;
+
+        assert!(!thread.check_canary());
+    }
+
+    #[test]
+    fn high_water_mark_counts_from_the_first_touched_byte() {
+        let mut thread = Thread::new();
+        thread.initialize(Box::new(|| {}), DEFAULT_PRIORITY, DEFAULT_STACK_SIZE, None).unwrap();
+        let initial = thread.stack_high_water_mark();
+        assert!(initial > 0 && initial < DEFAULT_STACK_SIZE);
+
+        thread.stack[DEFAULT_STACK_SIZE / 2] = 0;
+
+        assert_eq!(thread.stack_high_water_mark(), DEFAULT_STACK_SIZE / 2);
+    }
+}
@@ -9,13 +9,15 @@ extern crate acid_io;
 extern crate alloc;
 use acid_io::{Read, Write};
 
+use crate::hal::hal;
+
 
 
 /// Sends raw data over the serial channel
 /// This is only marked as unsafe because it has no checks and should
 /// not be used by anything other than a wrapper struct.
-unsafe fn send_serial_raw(mut data: Vec<u8>) {
-    crate::libv5rt::vexSerialWriteBuffer(1, data.as_mut_ptr(), data.len() as u32);
+unsafe fn send_serial_raw(data: Vec<u8>) {
+    hal().write(&data);
 }
 
 
@@ -38,15 +40,11 @@ impl Read for Serial {
         // Read until we have enough bytes
         while self.buffer.len() < buf.len() {
 
-            // Read in the data
-            let data = unsafe { crate::libv5rt::vexSerialReadChar(1) };
-
-            // If it is out of bounds exit the loop
-            if !(0..=0xff).contains(&data) {
-                break;
+            // Read in the data, exiting the loop if there is none
+            match hal().read_byte() {
+                Some(data) => self.buffer.push(data),
+                None => break,
             }
-
-            self.buffer.push(data as u8);
         }
         
         // Figure how many bytes we have to copy over
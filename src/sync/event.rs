@@ -0,0 +1,153 @@
+// An event that tasks can wait on until another task sets it
+
+use crate::hal::hal;
+use crate::runtime::thread::{Task, WakeupSignal};
+use super::wait_queue::{Inner, State, WaitQueue};
+
+/// The state of an event
+struct Flag {
+    /// Whether the event is set
+    set: bool,
+}
+
+impl State for Flag {
+    type Want = ();
+
+    /// Returns straight away if the event is set
+    fn take(inner: &mut Inner<Flag>, _task: Task, _: ()) -> bool {
+        inner.state.set
+    }
+
+    /// A killed task holds nothing, so there is nothing to pass on
+    fn abandon(_inner: &mut Inner<Flag>, _task: Task, _granted: bool) {}
+}
+
+/// A flag that tasks can wait to be set. Setting it wakes every waiting task, and it stays set
+/// until it is reset, so tasks that wait afterwards return straight away.
+pub struct Event {
+    /// Whether the event is set and the tasks waiting for it
+    queue: WaitQueue<Flag>,
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Event {
+    /// Creates an event that is not set
+    pub const fn new() -> Event {
+        Event {
+            queue: WaitQueue::new(Flag { set: false }, WakeupSignal::EventSet),
+        }
+    }
+
+    /// Returns true if the event is set
+    pub fn is_set(&self) -> bool {
+        self.queue.with(|inner| inner.state.set)
+    }
+
+    /// Sets the event, waking every task waiting for it. They return even if the event is reset before they run.
+    pub fn set(&self) {
+        self.queue.with(|inner| {
+            inner.state.set = true;
+            while let Some(position) = inner.first_waiting() {
+                inner.grant(position);
+            }
+        });
+    }
+
+    /// Clears the event, so that tasks wait for it to be set again
+    pub fn reset(&self) {
+        self.queue.with(|inner| inner.state.set = false);
+    }
+
+    /// Waits for the event to be set, for as long as it takes
+    pub fn wait(&self) {
+        self.queue.wait((), None);
+    }
+
+    /// Waits for the event to be set, giving up and returning false if it is not set within `ms` milliseconds
+    pub fn wait_timeout(&self, ms: u32) -> bool {
+        self.queue.wait((), Some(hal().time_ms().saturating_add(ms)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    use crate::RUNTIME;
+    use crate::runtime::tests::{priority_of, serial, spawn, spawn_with_priority};
+    use super::Event;
+
+    #[test]
+    fn setting_wakes_every_waiter() {
+        let _serial = serial();
+
+        let event = Arc::new(Event::new());
+        let woken = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..3).map(|_| {
+            let (event, woken) = (event.clone(), woken.clone());
+            spawn(move || {
+                event.wait();
+                woken.fetch_add(1, Ordering::SeqCst);
+            })
+        }).collect();
+
+        // Waiting again does not wake anyone
+        RUNTIME.yield_next();
+        RUNTIME.yield_next();
+        assert_eq!(woken.load(Ordering::SeqCst), 0);
+
+        event.set();
+        for handle in handles {
+            handle.join();
+        }
+        assert_eq!(woken.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn event_stays_set_until_reset() {
+        let _serial = serial();
+
+        let event = Event::new();
+        event.set();
+        assert!(event.is_set());
+
+        // Waiting on a set event returns straight away
+        event.wait();
+        assert!(event.wait_timeout(0));
+
+        event.reset();
+        assert!(!event.is_set());
+        assert!(!event.wait_timeout(0));
+    }
+
+    #[test]
+    fn waiters_return_even_if_reset_before_they_run() {
+        let _serial = serial();
+
+        let event = Arc::new(Event::new());
+        let low = priority_of(RUNTIME.current_task()) - 1;
+
+        // The waiters have a lower priority, so they do not run until we sleep
+        let handles: Vec<_> = (0..2).map(|_| {
+            let event = event.clone();
+            spawn_with_priority(move || event.wait_timeout(1000), low)
+        }).collect();
+        RUNTIME.sleep_ms(1);
+
+        event.set();
+        event.reset();
+
+        for handle in handles {
+            assert_eq!(handle.join(), Some(true));
+        }
+        assert!(!event.is_set());
+    }
+}
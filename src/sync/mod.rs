@@ -1,3 +1,18 @@
 
+/// The queue of tasks waiting on a primitive, shared by all of them
+pub(crate) mod wait_queue;
+
 /// A basic mutex implementation
-pub mod mutex;
\ No newline at end of file
+pub mod mutex;
+
+/// A read-write lock that prefers writers
+pub mod rwlock;
+pub use rwlock::RwLock;
+
+/// A counting semaphore
+pub mod semaphore;
+pub use semaphore::Semaphore;
+
+/// An event that tasks can wait to be set
+pub mod event;
+pub use event::Event;
@@ -2,98 +2,149 @@
 // This article was used as reference:
 // https://mnwa.medium.com/building-a-stupid-mutex-in-the-rust-d55886538889
 
-use core::{cell::{UnsafeCell, RefCell}, ops::{Deref, DerefMut}};
-use alloc::collections::VecDeque;
-use crate::runtime::thread::WakeupSignal;
+use core::{cell::UnsafeCell, marker::PhantomData, ops::{Deref, DerefMut}};
+use crate::hal::hal;
+use crate::runtime::thread::{Task, WakeupSignal};
+use super::wait_queue::{Inner, State, WaitQueue};
 
+/// The state of a mutex
+struct Lock {
+    /// The task holding the lock, if it is taken
+    owner: Option<Task>,
+}
+
+impl Lock {
+    /// Gives up the lock, handing it straight to the longest waiting task if there is one
+    fn hand_over(inner: &mut Inner<Lock>) {
+        if let Some(owner) = inner.state.owner.take() {
+            inner.unhold(owner);
+        }
+
+        if let Some(next) = inner.first_waiting() {
+            let task = inner.waiters[next].task;
+            inner.state.owner = Some(task);
+            inner.hold(task);
+            inner.grant(next);
+        }
+    }
+}
+
+impl State for Lock {
+    type Want = ();
+
+    /// Takes the lock if it is free. It is never free while anyone waits, as it is handed straight over.
+    fn take(inner: &mut Inner<Lock>, task: Task, _: ()) -> bool {
+        if inner.state.owner.is_some() {
+            return false;
+        }
 
-/// A basic mutex implementation
+        inner.state.owner = Some(task);
+        inner.hold(task);
+        true
+    }
+
+    /// Hands the lock on if the killed task held it, or had it handed over and never picked it up
+    fn abandon(inner: &mut Inner<Lock>, task: Task, _granted: bool) {
+        if inner.state.owner == Some(task) {
+            Lock::hand_over(inner);
+        }
+    }
+
+    fn holder(&self) -> Option<Task> {
+        self.owner
+    }
+}
+
+/// A basic mutex implementation. The lock is handed straight to the longest waiting task when it is released,
+/// so tasks get the lock in the order they asked for it. While a task waits, the holder runs at the
+/// waiter's priority if it is higher, so that tasks in between can not hold up the waiter indefinitely.
+/// If the holder is killed, the lock is handed on as if it had been released.
 pub struct Mutex<T> {
-    /// A boolean that determines if the lock is currently taken
-    lock: RefCell<bool>,
-    /// A queue of tasks waiting on the lock
-    queue: RefCell<VecDeque<usize>>,
+    /// The task holding the lock and the tasks waiting on it
+    queue: WaitQueue<Lock>,
     /// The data the mutex is storing
     data: UnsafeCell<T>
 }
 
 impl<T: Default> Default for Mutex<T> {
     fn default() -> Self {
-        Self {
-            lock: RefCell::new(false),
-            queue: RefCell::new(VecDeque::new()),
-            data: UnsafeCell::new(T::default())
-        }
+        Self::new(T::default())
     }
 }
 
 impl<T> Mutex<T> {
     /// Creates a new mutex
-    pub fn new(data: T) -> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
         Mutex {
-            lock: RefCell::new(false),
-            queue: RefCell::new(VecDeque::new()),
+            queue: WaitQueue::new(Lock { owner: None }, WakeupSignal::MutexRelease),
             data: UnsafeCell::new(data),
         }
     }
 
     /// Returns true if the lock is taken
     pub fn is_taken(&self) -> bool {
-        *self.lock.borrow()
+        self.queue.with(|inner| inner.state.owner.is_some())
     }
 
-    /// Acquires the lock on the mutex
-    #[allow(clippy::while_immutable_condition)]
-    pub fn acquire(&self) -> MutexGuard<'_, T>{
-        // If the lock is not acquired and no-one is waiting on the queue, then take the lock
-        if !*self.lock.borrow() && self.queue.borrow().is_empty() {
-            *self.lock.borrow_mut() = true;
-
-            return MutexGuard { mutex: self };
-        }
-
-        // Add ourselves to the queue
-        self.queue.borrow_mut().push_front(crate::RUNTIME.current_task());
-
-        // And go to sleep until we recieve the mutex unlocked signal, repeating for 
-        // as long as the lock is taken
-        while *self.lock.borrow() {
-            crate::RUNTIME.await_wake(WakeupSignal::MutexRelease);
-        }
-
-        // Once we are woken and the lock is not taken, set the lock
-        *self.lock.borrow_mut() = true;
+    /// Acquires the lock on the mutex, waiting for as long as it takes.
+    /// Panics if the current task already holds the lock, as waiting for itself would never finish.
+    pub fn acquire(&self) -> MutexGuard<'_, T> {
+        self.acquire_until(None).unwrap()
+    }
 
-        // Return the mutex guard
-        MutexGuard { mutex: self }
+    /// Acquires the lock on the mutex, giving up and returning None if it is not acquired within `ms` milliseconds.
+    /// Panics if the current task already holds the lock.
+    pub fn acquire_timeout(&self, ms: u32) -> Option<MutexGuard<'_, T>> {
+        self.acquire_until(Some(hal().time_ms().saturating_add(ms)))
     }
 
-    /// Releases the lock on the mutex
-    pub fn release(&self) {
-        // Release the lock
-        *self.lock.borrow_mut() = false;
+    /// Acquires the lock on the mutex, waiting until the system time (in milliseconds) reaches the deadline if there is one
+    fn acquire_until(&self, deadline: Option<u32>) -> Option<MutexGuard<'_, T>> {
+        let current = crate::RUNTIME.current();
 
-        // If the queue is empty, just return
-        if self.queue.borrow().is_empty() {
-            return;
+        // Only this task can make itself the owner, so this can not change while we wait
+        if self.queue.with(|inner| inner.state.owner == Some(current)) {
+            panic!("Task {} tried to acquire a mutex it already holds", current.id.0);
         }
 
-        // If not, get the next id and wake it
-        let next = self.queue.borrow_mut().pop_back();
+        self.queue.wait((), deadline).then(|| MutexGuard::new(self, current))
+    }
 
-        // If there is another task then wake it
-        if let Some(next) = next {
-            crate::RUNTIME.wake(next, WakeupSignal::MutexRelease);
-        }
+    /// Acquires the lock on the mutex if it is free, without waiting.
+    /// Returns None if another task, or the current one, holds the lock.
+    pub fn try_acquire(&self) -> Option<MutexGuard<'_, T>> {
+        let current = crate::RUNTIME.current();
 
+        self.queue.with(|inner| Lock::take(inner, current, ())).then(|| MutexGuard::new(self, current))
     }
 
+    /// Releases the lock on the mutex, handing it to the next task in the queue.
+    /// Only the guard calls this, as only the task holding the lock may release it. Nothing happens if
+    /// the task no longer holds it, as when a killed task's lock was handed on before its guard was dropped.
+    fn release(&self, owner: Task) {
+        self.queue.with(|inner| {
+            if inner.state.owner == Some(owner) {
+                Lock::hand_over(inner);
+            }
+        });
+    }
 }
 
 
-/// A guard smart pointer for the mutex
+/// A guard smart pointer for the mutex. It can not be sent to another task,
+/// as the lock must be released by the task that acquired it.
 pub struct MutexGuard<'a, T> {
-    mutex: &'a Mutex<T>
+    mutex: &'a Mutex<T>,
+    /// The task that acquired the lock
+    owner: Task,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Creates a guard for a mutex a task has just acquired
+    fn new(mutex: &'a Mutex<T>, owner: Task) -> MutexGuard<'a, T> {
+        MutexGuard { mutex, owner, _not_send: PhantomData }
+    }
 }
 
 impl<T> Deref for MutexGuard<'_, T> {
@@ -121,13 +172,483 @@ impl<T> Drop for MutexGuard<'_, T> {
     
     /// Releases the contained mutex when the guard is dropped
     fn drop(&mut self) {
-        self.mutex.release()
+        self.mutex.release(self.owner)
     }
 }
 
 
-// Force send ans sync on the mutex.
-
+// The lock state and queue are only touched inside critical sections,
+// so the mutex can be shared between tasks as long as the data can be sent between them.
 unsafe impl<T> Send for Mutex<T> where T: Send {}
-unsafe impl<T> Sync for Mutex<T> where T: Send {}unsafe impl<T> Send for MutexGuard<'_, T> where T: Send {}
-unsafe impl<T> Sync for MutexGuard<'_, T> where T: Send + Sync {}
\ No newline at end of file
+unsafe impl<T> Sync for Mutex<T> where T: Send {}
+unsafe impl<T> Sync for MutexGuard<'_, T> where T: Send + Sync {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::vec::Vec;
+    use std::vec;
+
+    use crate::RUNTIME;
+    use crate::hal::Mock;
+    use crate::runtime::tests::{priority_of, serial, spawn, spawn_with_priority, use_mock};
+    use super::Mutex;
+
+    #[test]
+    fn waiters_acquire_in_order() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+        let guard = mutex.acquire();
+
+        let handles: Vec<_> = ["a", "b", "c"].into_iter().map(|name| {
+            let mutex = mutex.clone();
+            spawn(move || mutex.acquire().push(name))
+        }).collect();
+
+        // Let every thread queue up behind the lock
+        RUNTIME.yield_next();
+        drop(guard);
+
+        for handle in handles {
+            handle.join();
+        }
+
+        assert_eq!(*mutex.acquire(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn released_lock_goes_to_the_waiter() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+
+        // Each thread holds the lock across a yield, so the other is always waiting when it is released
+        let handles: Vec<_> = ["a", "b"].into_iter().map(|name| {
+            let mutex = mutex.clone();
+            spawn(move || {
+                for _ in 0..3 {
+                    let mut guard = mutex.acquire();
+                    guard.push(name);
+                    RUNTIME.yield_next();
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join();
+        }
+
+        assert_eq!(*mutex.acquire(), vec!["a", "b", "a", "b", "a", "b"]);
+    }
+
+    #[test]
+    fn acquire_queues_behind_waiters() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+        let guard = mutex.acquire();
+
+        let handle = {
+            let mutex = mutex.clone();
+            spawn(move || mutex.acquire().push("waiter"))
+        };
+
+        RUNTIME.yield_next();
+        drop(guard);
+
+        // The waiter was handed the lock, so taking it again has to wait for the waiter
+        mutex.acquire().push("releaser");
+        handle.join();
+
+        assert_eq!(*mutex.acquire(), vec!["waiter", "releaser"]);
+    }
+
+    #[test]
+    fn try_acquire_fails_while_taken() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.try_acquire().unwrap();
+        assert!(mutex.try_acquire().is_none());
+
+        let handle = {
+            let mutex = mutex.clone();
+            spawn(move || mutex.try_acquire().is_some())
+        };
+        assert_eq!(handle.join(), Some(false));
+
+        drop(guard);
+        assert!(!mutex.is_taken());
+        assert!(mutex.try_acquire().is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "already holds")]
+    fn acquiring_twice_panics() {
+        let _serial = serial();
+
+        let mutex = Mutex::new(0);
+        let _guard = mutex.acquire();
+        let _again = mutex.acquire();
+    }
+
+    #[test]
+    fn killed_holder_hands_the_lock_to_its_waiter() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+        let holder = {
+            let mutex = mutex.clone();
+            spawn(move || {
+                let _guard = mutex.acquire();
+                loop {
+                    RUNTIME.yield_next();
+                }
+            })
+        };
+        let waiter = {
+            let mutex = mutex.clone();
+            spawn(move || mutex.acquire().push("waiter"))
+        };
+
+        while mutex.queue.with(|inner| inner.waiters.is_empty()) {
+            RUNTIME.yield_next();
+        }
+
+        // The holder never releases the lock itself
+        assert!(RUNTIME.kill(holder.id()));
+        waiter.join();
+        assert_eq!(*mutex.acquire(), vec!["waiter"]);
+    }
+
+    #[test]
+    fn guard_kept_in_a_killed_threads_local_does_not_release_the_next_holder() {
+        let _serial = serial();
+
+        static MUTEX: Mutex<()> = Mutex::new(());
+        crate::thread_local! {
+            static GUARD: core::cell::RefCell<Option<super::MutexGuard<'static, ()>>> = core::cell::RefCell::new(None);
+        }
+
+        let holder = spawn(|| {
+            GUARD.with(|guard| *guard.borrow_mut() = Some(MUTEX.acquire()));
+            loop {
+                RUNTIME.yield_next();
+            }
+        });
+
+        let inside = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (inside, done) = (inside.clone(), done.clone());
+            spawn(move || {
+                let _guard = MUTEX.acquire();
+                inside.store(true, Ordering::SeqCst);
+                while !done.load(Ordering::SeqCst) {
+                    RUNTIME.yield_next();
+                }
+            })
+        };
+
+        while MUTEX.queue.with(|inner| inner.waiters.is_empty()) {
+            RUNTIME.yield_next();
+        }
+
+        // Dropping the holder's guard hands the lock to the waiter, and nothing can take it from the waiter after
+        assert!(RUNTIME.kill(holder.id()));
+        while !inside.load(Ordering::SeqCst) {
+            RUNTIME.yield_next();
+        }
+        assert!(MUTEX.try_acquire().is_none());
+
+        done.store(true, Ordering::SeqCst);
+        waiter.join();
+        assert!(!MUTEX.is_taken());
+    }
+
+    #[test]
+    fn mutex_can_be_dropped_while_a_leaked_guard_holds_it() {
+        let _serial = serial();
+
+        // The thread still holds the mutex when it finishes, after the mutex is gone
+        let handle = spawn(|| {
+            let mutex = std::boxed::Box::new(Mutex::new(0));
+            core::mem::forget(mutex.acquire());
+            drop(mutex);
+
+            // Something else takes the mutex's place in memory
+            vec![0xffu8; core::mem::size_of::<Mutex<i32>>()]
+        });
+
+        assert!(handle.join().is_some());
+    }
+
+    #[test]
+    fn thread_reusing_a_killed_holders_slot_can_take_the_lock() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(0));
+        let holder = {
+            let mutex = mutex.clone();
+            spawn(move || {
+                let _guard = mutex.acquire();
+                loop {
+                    RUNTIME.yield_next();
+                }
+            })
+        };
+
+        RUNTIME.yield_next();
+        assert!(mutex.is_taken());
+        assert!(RUNTIME.kill(holder.id()));
+        assert!(!mutex.is_taken());
+
+        // The new thread is not mistaken for the killed one, which held the lock in the same slot
+        let reuser = {
+            let mutex = mutex.clone();
+            spawn(move || {
+                *mutex.try_acquire().unwrap() += 1;
+                *mutex.acquire() += 1;
+            })
+        };
+        assert_eq!(reuser.id(), holder.id());
+
+        reuser.join();
+        assert_eq!(*mutex.acquire(), 2);
+    }
+
+    #[test]
+    fn acquire_timeout_gives_up_and_leaves_the_queue() {
+        let _serial = serial();
+
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.acquire();
+
+        let handle = {
+            let mutex = mutex.clone();
+            spawn(move || mutex.acquire_timeout(10).is_some())
+        };
+
+        // The waiter queues up, and is still waiting just before its deadline
+        RUNTIME.yield_next();
+        MOCK.advance_ms(9);
+        RUNTIME.yield_next();
+        assert_eq!(mutex.queue.with(|inner| inner.waiters.len()), 1);
+
+        MOCK.advance_ms(1);
+        assert_eq!(handle.join(), Some(false));
+        assert!(mutex.queue.with(|inner| inner.waiters.is_empty()));
+
+        // Nobody is waiting any more, so releasing leaves the lock free
+        drop(guard);
+        assert!(!mutex.is_taken());
+    }
+
+    #[test]
+    fn acquire_timeout_gets_a_released_lock() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.acquire();
+
+        let handle = {
+            let mutex = mutex.clone();
+            spawn(move || mutex.acquire_timeout(1000).map(|mut guard| *guard += 1).is_some())
+        };
+
+        RUNTIME.yield_next();
+        drop(guard);
+
+        assert_eq!(handle.join(), Some(true));
+        assert_eq!(*mutex.acquire(), 1);
+    }
+
+    #[test]
+    fn holder_inherits_the_waiters_priority_until_release() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(0));
+        let guard = mutex.acquire();
+        let holder = RUNTIME.current_task();
+        let base = priority_of(holder);
+
+        let waiter = {
+            let mutex = mutex.clone();
+            spawn_with_priority(move || *mutex.acquire() += 1, base + 1)
+        };
+
+        // The waiter runs first as it has a higher priority, and lends us its priority while it waits
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(holder), base + 1);
+
+        // Releasing gives the priority back, so the waiter runs straight away
+        drop(guard);
+        assert_eq!(priority_of(holder), base);
+        assert_eq!(*mutex.acquire(), 1);
+
+        waiter.join();
+    }
+
+    #[test]
+    fn holder_keeps_only_the_priority_lent_through_locks_it_still_holds() {
+        let _serial = serial();
+
+        let (first, second) = (Arc::new(Mutex::new(0)), Arc::new(Mutex::new(0)));
+        let first_guard = first.acquire();
+        let second_guard = second.acquire();
+        let holder = RUNTIME.current_task();
+        let base = priority_of(holder);
+
+        let low_waiter = {
+            let second = second.clone();
+            spawn_with_priority(move || *second.acquire() += 1, base + 1)
+        };
+        RUNTIME.yield_next();
+        let high_waiter = {
+            let first = first.clone();
+            spawn_with_priority(move || *first.acquire() += 1, base + 2)
+        };
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(holder), base + 2);
+
+        // The other waiter is still waiting on a lock we hold
+        drop(first_guard);
+        assert_eq!(*first.try_acquire().unwrap(), 1);
+        assert_eq!(priority_of(holder), base + 1);
+
+        drop(second_guard);
+        assert_eq!(priority_of(holder), base);
+
+        high_waiter.join();
+        low_waiter.join();
+        assert_eq!(*second.acquire(), 1);
+    }
+
+    #[test]
+    fn waiter_that_gives_up_or_is_killed_stops_lending_its_priority() {
+        let _serial = serial();
+
+        static MOCK: Mock = Mock::new();
+        let _restore = use_mock(&MOCK);
+
+        let mutex = Arc::new(Mutex::new(0));
+        let _guard = mutex.acquire();
+        let holder = RUNTIME.current_task();
+        let base = priority_of(holder);
+
+        let timing_out = {
+            let mutex = mutex.clone();
+            spawn_with_priority(move || mutex.acquire_timeout(10).is_some(), base + 1)
+        };
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(holder), base + 1);
+
+        MOCK.advance_ms(10);
+        RUNTIME.yield_next();
+        assert_eq!(timing_out.join(), Some(false));
+        assert_eq!(priority_of(holder), base);
+
+        let killed = {
+            let mutex = mutex.clone();
+            spawn_with_priority(move || *mutex.acquire() += 1, base + 1)
+        };
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(holder), base + 1);
+
+        assert!(RUNTIME.kill(killed.id()));
+        assert_eq!(priority_of(holder), base);
+    }
+
+    #[test]
+    fn priority_is_lent_along_a_chain_of_waiters() {
+        let _serial = serial();
+
+        let (first, second) = (Arc::new(Mutex::new(())), Arc::new(Mutex::new(())));
+        let guard = first.acquire();
+        let holder = RUNTIME.current_task();
+        let base = priority_of(holder);
+
+        // The middle thread takes the second lock, then waits on the first one we hold
+        let middle = {
+            let (first, second) = (first.clone(), second.clone());
+            spawn_with_priority(move || {
+                let _second = second.acquire();
+                let _first = first.acquire();
+            }, base + 1)
+        };
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(holder), base + 1);
+
+        // The high thread waits on the middle thread, which is waiting on us
+        let high = {
+            let second = second.clone();
+            spawn_with_priority(move || drop(second.acquire()), base + 2)
+        };
+        RUNTIME.yield_next();
+        assert_eq!(priority_of(middle.id()), base + 2);
+        assert_eq!(priority_of(holder), base + 2);
+
+        drop(guard);
+        assert_eq!(priority_of(holder), base);
+
+        middle.join();
+        high.join();
+    }
+
+    #[test]
+    fn priority_inversion_is_bounded() {
+        let _serial = serial();
+
+        let mutex = Arc::new(Mutex::new(()));
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let go = Arc::new(AtomicBool::new(false));
+
+        // A low priority thread takes the lock and holds it until told to carry on
+        let low = {
+            let (mutex, log, go) = (mutex.clone(), log.clone(), go.clone());
+            spawn_with_priority(move || {
+                let _guard = mutex.acquire();
+                while !go.load(Ordering::SeqCst) {
+                    RUNTIME.yield_next();
+                }
+                log.lock().unwrap().push("low");
+            }, 1)
+        };
+
+        while !mutex.is_taken() {
+            RUNTIME.sleep_ms(1);
+        }
+
+        // A high priority thread needs the lock, while a middle priority thread that does not
+        // keeps running for as long as nothing of a higher priority is ready
+        let high = {
+            let (mutex, log) = (mutex.clone(), log.clone());
+            spawn_with_priority(move || {
+                let _guard = mutex.acquire();
+                log.lock().unwrap().push("high");
+            }, 9)
+        };
+        let middle = {
+            let log = log.clone();
+            spawn_with_priority(move || {
+                for _ in 0..100 {
+                    RUNTIME.yield_next();
+                }
+                log.lock().unwrap().push("middle");
+            }, 5)
+        };
+
+        // Without inheritance the middle thread would finish before the low thread got to release the lock
+        go.store(true, Ordering::SeqCst);
+        high.join();
+        middle.join();
+        low.join();
+
+        assert_eq!(*log.lock().unwrap(), vec!["low", "high", "middle"]);
+    }
+}
@@ -0,0 +1,519 @@
+// A read-write lock built on the runtime's wake signals.
+// Waiting writers hold back new readers, so a steady stream of readers can not starve a writer.
+
+use core::{cell::UnsafeCell, marker::PhantomData, ops::{Deref, DerefMut}};
+use alloc::vec::Vec;
+use crate::runtime::thread::{Task, WakeupSignal};
+use super::wait_queue::{Inner, State, WaitQueue};
+
+/// What a task waiting on the lock wants to do
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Read alongside other readers
+    Read,
+    /// Write with nobody else reading or writing
+    Write,
+}
+
+/// The state of a read-write lock
+struct Holders {
+    /// The task writing, if there is one
+    writer: Option<Task>,
+    /// The tasks reading, once for every read lock they hold
+    readers: Vec<Task>,
+}
+
+impl Holders {
+    /// Starts reading for a task
+    fn add_reader(inner: &mut Inner<Holders>, task: Task) {
+        inner.state.readers.push(task);
+        inner.hold(task);
+    }
+
+    /// Starts writing for a task
+    fn set_writer(inner: &mut Inner<Holders>, task: Task) {
+        inner.state.writer = Some(task);
+        inner.hold(task);
+    }
+
+    /// Hands the lock to the oldest waiting writer if nobody holds it, or lets in every reader
+    /// queued ahead of it if nobody is writing
+    fn hand_over(inner: &mut Inner<Holders>) {
+        if inner.state.writer.is_some() {
+            return;
+        }
+
+        let Some(first) = inner.first_waiting() else {
+            return;
+        };
+
+        if inner.waiters[first].want == Access::Write {
+            if inner.state.readers.is_empty() {
+                let task = inner.waiters[first].task;
+                Holders::set_writer(inner, task);
+                inner.grant(first);
+            }
+            return;
+        }
+
+        let mut position = first;
+        while position < inner.waiters.len() && inner.waiters[position].want == Access::Read {
+            if !inner.waiters[position].granted {
+                let task = inner.waiters[position].task;
+                Holders::add_reader(inner, task);
+                inner.grant(position);
+            }
+            position += 1;
+        }
+    }
+}
+
+impl State for Holders {
+    type Want = Access;
+
+    /// Starts reading if nobody is writing or waiting to write, or writing if nobody is reading or writing
+    fn take(inner: &mut Inner<Holders>, task: Task, want: Access) -> bool {
+        if inner.state.writer.is_some() {
+            return false;
+        }
+
+        match want {
+            Access::Read if inner.waiters.iter().all(|waiter| waiter.want == Access::Read) => {
+                Holders::add_reader(inner, task);
+                true
+            }
+            Access::Write if inner.state.readers.is_empty() => {
+                Holders::set_writer(inner, task);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stops the killed task reading or writing, including any lock it was handed and never picked up,
+    /// and lets in whoever can go next. Readers held back by a killed waiting writer can go in too.
+    fn abandon(inner: &mut Inner<Holders>, task: Task, _granted: bool) {
+        if inner.state.writer == Some(task) {
+            inner.state.writer = None;
+            inner.unhold(task);
+        }
+
+        while let Some(position) = inner.state.readers.iter().position(|reader| *reader == task) {
+            inner.state.readers.swap_remove(position);
+            inner.unhold(task);
+        }
+
+        Holders::hand_over(inner);
+    }
+
+    fn holder(&self) -> Option<Task> {
+        self.writer
+    }
+}
+
+/// A lock that any number of tasks can read through at once, or one task can write through.
+/// Once a writer is waiting, new readers wait behind it. When the lock is released it is handed
+/// straight to the next waiting writer, or to every reader queued ahead of it.
+/// If a task is killed while reading or writing, it stops as if it had released the lock.
+pub struct RwLock<T> {
+    /// The tasks reading or writing and the tasks waiting on the lock
+    queue: WaitQueue<Holders>,
+    /// The data the lock is protecting
+    data: UnsafeCell<T>,
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> RwLock<T> {
+    /// Creates a new read-write lock
+    pub const fn new(data: T) -> RwLock<T> {
+        RwLock {
+            queue: WaitQueue::new(Holders { writer: None, readers: Vec::new() }, WakeupSignal::RwLockRelease),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Returns true if a task is writing
+    pub fn is_writing(&self) -> bool {
+        self.queue.with(|inner| inner.state.writer.is_some())
+    }
+
+    /// Gets the number of tasks reading
+    pub fn readers(&self) -> usize {
+        self.queue.with(|inner| inner.state.readers.len())
+    }
+
+    /// Panics if the current task is writing, as waiting for itself would never finish
+    fn check_not_writing(&self, current: Task) {
+        // Only this task can make itself the writer, so this can not change while we wait
+        if self.queue.with(|inner| inner.state.writer == Some(current)) {
+            panic!("Task {} tried to acquire a read-write lock it is writing to", current.id.0);
+        }
+    }
+
+    /// Locks the lock for reading, waiting while a task is writing or waiting to write.
+    /// Panics if the current task is writing. Reading again while already reading waits
+    /// if a writer has started waiting in between, so it can deadlock.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let current = crate::RUNTIME.current();
+        self.check_not_writing(current);
+        self.queue.wait(Access::Read, None);
+
+        RwLockReadGuard { lock: self, reader: current, _not_send: PhantomData }
+    }
+
+    /// Locks the lock for reading if nobody is writing or waiting to write, without waiting
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let current = crate::RUNTIME.current();
+
+        self.queue.with(|inner| Holders::take(inner, current, Access::Read))
+            .then(|| RwLockReadGuard { lock: self, reader: current, _not_send: PhantomData })
+    }
+
+    /// Locks the lock for writing, waiting until nobody is reading or writing.
+    /// Panics if the current task is already writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let current = crate::RUNTIME.current();
+        self.check_not_writing(current);
+        self.queue.wait(Access::Write, None);
+
+        RwLockWriteGuard { lock: self, writer: current, _not_send: PhantomData }
+    }
+
+    /// Locks the lock for writing if nobody is reading or writing, without waiting
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let current = crate::RUNTIME.current();
+
+        self.queue.with(|inner| Holders::take(inner, current, Access::Write))
+            .then(|| RwLockWriteGuard { lock: self, writer: current, _not_send: PhantomData })
+    }
+
+    /// Stops a task reading, handing the lock on if it was the last reader
+    fn release_read(&self, reader: Task) {
+        self.queue.with(|inner| {
+            if let Some(position) = inner.state.readers.iter().position(|task| *task == reader) {
+                inner.state.readers.swap_remove(position);
+                inner.unhold(reader);
+            }
+
+            Holders::hand_over(inner);
+        });
+    }
+
+    /// Stops a task writing and hands the lock on. Nothing happens if the task is no longer writing,
+    /// as when a killed task's lock was handed on before its guard was dropped.
+    fn release_write(&self, writer: Task) {
+        self.queue.with(|inner| {
+            if inner.state.writer != Some(writer) {
+                return;
+            }
+
+            inner.state.writer = None;
+            inner.unhold(writer);
+            Holders::hand_over(inner);
+        });
+    }
+}
+
+/// A guard that reads through a read-write lock. It can not be sent to another task,
+/// as the lock must be released by the task that locked it.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// The task that is reading
+    reader: Task,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    /// Derefs the contents of the lock
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            &*self.lock.data.get()
+        }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    /// Stops reading when the guard is dropped
+    fn drop(&mut self) {
+        self.lock.release_read(self.reader)
+    }
+}
+
+/// A guard that writes through a read-write lock. It can not be sent to another task,
+/// as the lock must be released by the task that locked it.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    /// The task that is writing
+    writer: Task,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    /// Derefs the contents of the lock
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            &*self.lock.data.get()
+        }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    /// Mutably derefs the contents of the lock
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe {
+            &mut *self.lock.data.get()
+        }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    /// Stops writing when the guard is dropped
+    fn drop(&mut self) {
+        self.lock.release_write(self.writer)
+    }
+}
+
+// The lock state and queue are only touched inside critical sections.
+// Readers share the data between tasks, so it must be Sync as well as Send.
+unsafe impl<T> Send for RwLock<T> where T: Send {}
+unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
+unsafe impl<T> Sync for RwLockReadGuard<'_, T> where T: Sync {}
+unsafe impl<T> Sync for RwLockWriteGuard<'_, T> where T: Sync {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::vec::Vec;
+    use std::vec;
+
+    use crate::RUNTIME;
+    use crate::runtime::tests::{priority_of, serial, spawn, spawn_with_priority};
+    use super::RwLock;
+
+    #[test]
+    fn readers_share_the_lock() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(7));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        // Every reader holds the lock across a yield, so they would take turns if reading was exclusive
+        let handles: Vec<_> = (0..5).map(|_| {
+            let (lock, most) = (lock.clone(), most.clone());
+            spawn(move || {
+                let value = lock.read();
+                most.fetch_max(lock.readers(), Ordering::SeqCst);
+                RUNTIME.yield_next();
+                *value
+            })
+        }).collect();
+
+        for handle in handles {
+            assert_eq!(handle.join(), Some(7));
+        }
+
+        assert_eq!(most.load(Ordering::SeqCst), 5);
+        assert_eq!(lock.readers(), 0);
+    }
+
+    #[test]
+    fn writer_waits_for_readers_and_excludes_everyone() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.read();
+
+        let writer = {
+            let lock = lock.clone();
+            spawn(move || *lock.write() += 1)
+        };
+
+        RUNTIME.yield_next();
+        assert!(!lock.is_writing());
+        assert!(lock.try_write().is_none());
+
+        drop(guard);
+        writer.join();
+
+        assert_eq!(*lock.read(), 1);
+
+        let guard = lock.write();
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(guard);
+        assert!(lock.try_read().is_some());
+    }
+
+    #[test]
+    fn waiting_writer_goes_before_new_readers() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(Vec::new()));
+        let guard = lock.read();
+
+        let writer = {
+            let lock = lock.clone();
+            spawn(move || lock.write().push("writer"))
+        };
+        RUNTIME.yield_next();
+
+        // A reader arriving while the writer waits has to wait behind it, even though the lock is only being read
+        assert!(lock.try_read().is_none());
+        let reader = {
+            let lock = lock.clone();
+            spawn(move || lock.read().len())
+        };
+        RUNTIME.yield_next();
+        assert_eq!(lock.readers(), 1);
+
+        drop(guard);
+        writer.join();
+        assert_eq!(reader.join(), Some(1));
+
+        assert_eq!(*lock.read(), vec!["writer"]);
+    }
+
+    #[test]
+    fn killed_writer_stops_writing() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(0));
+        let writer = {
+            let lock = lock.clone();
+            spawn(move || {
+                let _guard = lock.write();
+                loop {
+                    RUNTIME.yield_next();
+                }
+            })
+        };
+        let reader = {
+            let lock = lock.clone();
+            spawn(move || *lock.read())
+        };
+
+        while lock.queue.with(|inner| inner.waiters.is_empty()) {
+            RUNTIME.yield_next();
+        }
+
+        // The waiting reader gets the lock, and so does whatever reuses the writer's slot
+        assert!(RUNTIME.kill(writer.id()));
+        assert_eq!(reader.join(), Some(0));
+
+        let reuser = {
+            let lock = lock.clone();
+            spawn(move || {
+                *lock.try_write().unwrap() += 1;
+                *lock.write() += 1;
+            })
+        };
+        assert_eq!(reuser.id(), writer.id());
+        reuser.join();
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn write_guard_kept_in_a_killed_threads_local_does_not_release_the_next_writer() {
+        let _serial = serial();
+
+        static LOCK: RwLock<()> = RwLock::new(());
+        crate::thread_local! {
+            static GUARD: core::cell::RefCell<Option<super::RwLockWriteGuard<'static, ()>>> = core::cell::RefCell::new(None);
+        }
+
+        let writer = spawn(|| {
+            GUARD.with(|guard| *guard.borrow_mut() = Some(LOCK.write()));
+            loop {
+                RUNTIME.yield_next();
+            }
+        });
+
+        let inside = Arc::new(AtomicBool::new(false));
+        let done = Arc::new(AtomicBool::new(false));
+        let next = {
+            let (inside, done) = (inside.clone(), done.clone());
+            spawn(move || {
+                let _guard = LOCK.write();
+                inside.store(true, Ordering::SeqCst);
+                while !done.load(Ordering::SeqCst) {
+                    RUNTIME.yield_next();
+                }
+            })
+        };
+
+        while LOCK.queue.with(|inner| inner.waiters.is_empty()) {
+            RUNTIME.yield_next();
+        }
+
+        // Dropping the killed writer's guard hands the lock on, and nothing can take it from the next writer after
+        assert!(RUNTIME.kill(writer.id()));
+        while !inside.load(Ordering::SeqCst) {
+            RUNTIME.yield_next();
+        }
+        assert!(LOCK.is_writing());
+        assert!(LOCK.try_read().is_none());
+
+        done.store(true, Ordering::SeqCst);
+        next.join();
+        assert!(!LOCK.is_writing());
+    }
+
+    #[test]
+    fn killed_reader_gives_up_a_lock_it_was_handed() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.write();
+
+        // The reader has a lower priority, so it is handed the lock but does not get to run
+        let reader = {
+            let lock = lock.clone();
+            spawn_with_priority(move || *lock.read(), priority_of(RUNTIME.current_task()) - 1)
+        };
+        RUNTIME.sleep_ms(1);
+        drop(guard);
+        assert_eq!(lock.readers(), 1);
+
+        assert!(RUNTIME.kill(reader.id()));
+        assert_eq!(lock.readers(), 0);
+        assert!(lock.queue.with(|inner| inner.waiters.is_empty()));
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn killed_waiting_writer_stops_holding_back_readers() {
+        let _serial = serial();
+
+        let lock = Arc::new(RwLock::new(0));
+        let guard = lock.read();
+
+        let writer = {
+            let lock = lock.clone();
+            spawn(move || *lock.write() += 1)
+        };
+        RUNTIME.yield_next();
+        let reader = {
+            let lock = lock.clone();
+            spawn(move || *lock.read())
+        };
+        RUNTIME.yield_next();
+        assert_eq!(lock.readers(), 1);
+
+        // The reader no longer has anyone to wait behind, so it reads alongside us
+        assert!(RUNTIME.kill(writer.id()));
+        assert_eq!(lock.readers(), 2);
+        assert_eq!(reader.join(), Some(0));
+        drop(guard);
+        assert_eq!(*lock.read(), 0);
+    }
+}
@@ -0,0 +1,198 @@
+// A counting semaphore built on the runtime's wake signals
+
+use crate::hal::hal;
+use crate::runtime::thread::{Task, WakeupSignal};
+use super::wait_queue::{Inner, State, WaitQueue};
+
+/// The state of a semaphore
+struct Permits {
+    /// The number of permits nobody has taken
+    available: usize,
+}
+
+impl Permits {
+    /// Hands a permit to the longest waiting task, or keeps it if nobody is waiting
+    fn give(inner: &mut Inner<Permits>) {
+        match inner.first_waiting() {
+            Some(position) => inner.grant(position),
+            None => inner.state.available += 1,
+        }
+    }
+}
+
+impl State for Permits {
+    type Want = ();
+
+    /// Takes a permit if there is one and nobody is waiting ahead of us
+    fn take(inner: &mut Inner<Permits>, _task: Task, _: ()) -> bool {
+        if inner.state.available == 0 || inner.first_waiting().is_some() {
+            return false;
+        }
+
+        inner.state.available -= 1;
+        true
+    }
+
+    /// Passes on a permit that was handed to the killed task before it could pick it up
+    fn abandon(inner: &mut Inner<Permits>, _task: Task, granted: bool) {
+        if granted {
+            Permits::give(inner);
+        }
+    }
+}
+
+/// A semaphore holding a number of permits. Acquiring takes a permit, waiting until one is released if there are none.
+/// Released permits are handed straight to waiting tasks in the order they started waiting.
+/// Permits are not tied to a task, so one task can release permits for another to acquire. For the same reason
+/// a task that is killed keeps the permits it acquired, but one that was handed to it while it waited is passed on.
+pub struct Semaphore {
+    /// The permits nobody has taken and the tasks waiting for one
+    queue: WaitQueue<Permits>,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with a number of permits
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            queue: WaitQueue::new(Permits { available: permits }, WakeupSignal::SemaphoreRelease),
+        }
+    }
+
+    /// Gets the number of permits that can be acquired without waiting
+    pub fn available(&self) -> usize {
+        self.queue.with(|inner| inner.state.available)
+    }
+
+    /// Takes a permit, waiting for as long as it takes
+    pub fn acquire(&self) {
+        self.queue.wait((), None);
+    }
+
+    /// Takes a permit, giving up and returning false if none is released within `ms` milliseconds
+    pub fn acquire_timeout(&self, ms: u32) -> bool {
+        self.queue.wait((), Some(hal().time_ms().saturating_add(ms)))
+    }
+
+    /// Takes a permit if one is available, without waiting. Returns false if there is none.
+    pub fn try_acquire(&self) -> bool {
+        let current = crate::RUNTIME.current();
+
+        self.queue.with(|inner| Permits::take(inner, current, ()))
+    }
+
+    /// Adds `n` permits, handing them to waiting tasks first
+    pub fn release(&self, n: usize) {
+        self.queue.with(|inner| {
+            for _ in 0..n {
+                Permits::give(inner);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::vec::Vec;
+
+    use crate::RUNTIME;
+    use crate::runtime::tests::{priority_of, serial, spawn, spawn_with_priority};
+    use super::Semaphore;
+
+    #[test]
+    fn permits_limit_how_many_tasks_run_at_once() {
+        let _serial = serial();
+
+        let semaphore = Arc::new(Semaphore::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        // Every task holds its permit across a few yields, so more would run at once without the limit
+        let handles: Vec<_> = (0..5).map(|_| {
+            let (semaphore, running, most) = (semaphore.clone(), running.clone(), most.clone());
+            spawn(move || {
+                semaphore.acquire();
+                most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                for _ in 0..3 {
+                    RUNTIME.yield_next();
+                }
+                running.fetch_sub(1, Ordering::SeqCst);
+                semaphore.release(1);
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join();
+        }
+
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(semaphore.available(), 2);
+    }
+
+    #[test]
+    fn release_hands_permits_to_waiters_in_order() {
+        let _serial = serial();
+
+        let semaphore = Arc::new(Semaphore::new(0));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        assert!(!semaphore.try_acquire());
+
+        let handles: Vec<_> = ["a", "b", "c"].into_iter().map(|name| {
+            let (semaphore, log) = (semaphore.clone(), log.clone());
+            spawn(move || {
+                semaphore.acquire();
+                log.lock().unwrap().push(name);
+            })
+        }).collect();
+
+        // Let every task start waiting, then release enough for two of them
+        RUNTIME.yield_next();
+        semaphore.release(2);
+        assert!(!semaphore.try_acquire());
+
+        let mut handles = handles.into_iter();
+        handles.next().unwrap().join();
+        handles.next().unwrap().join();
+        assert_eq!(*log.lock().unwrap(), ["a", "b"]);
+
+        // Only one task is left waiting, so one of these permits is kept
+        semaphore.release(2);
+        for handle in handles {
+            handle.join();
+        }
+        assert_eq!(*log.lock().unwrap(), ["a", "b", "c"]);
+        assert_eq!(semaphore.available(), 1);
+        assert!(semaphore.try_acquire());
+    }
+
+    #[test]
+    fn permit_handed_to_a_killed_waiter_goes_to_the_next_one() {
+        let _serial = serial();
+
+        let semaphore = Arc::new(Semaphore::new(0));
+        let low = priority_of(RUNTIME.current_task()) - 1;
+
+        // The waiters have a lower priority, so they are handed permits but do not get to run
+        let handles: Vec<_> = (0..2).map(|_| {
+            let semaphore = semaphore.clone();
+            spawn_with_priority(move || semaphore.acquire_timeout(1000), low)
+        }).collect();
+        RUNTIME.sleep_ms(1);
+
+        semaphore.release(1);
+        let mut handles = handles.into_iter();
+        let killed = handles.next().unwrap();
+        assert!(RUNTIME.kill(killed.id()));
+        assert_eq!(handles.next().unwrap().join(), Some(true));
+        assert_eq!(semaphore.available(), 0);
+
+        // Whatever reuses the killed waiter's slot does not find a permit left for it
+        let reuser = {
+            let semaphore = semaphore.clone();
+            spawn(move || semaphore.try_acquire())
+        };
+        assert_eq!(reuser.id(), killed.id());
+        assert_eq!(reuser.join(), Some(false));
+    }
+}
@@ -0,0 +1,242 @@
+// The queue of tasks waiting on a synchronization primitive, shared by all of them.
+// Each primitive keeps its own state alongside the queue and decides when a task can have what it asks for,
+// while the queue takes care of going to sleep, timing out, handing over and killed tasks.
+
+use core::cell::UnsafeCell;
+use alloc::{collections::VecDeque, vec::Vec};
+use crate::hal::hal;
+use crate::runtime::CriticalSection;
+use crate::runtime::resource::{Resource, ResourceRef};
+use crate::runtime::thread::{Task, WakeupSignal};
+
+/// The part of a primitive that differs from the others, which the queue calls inside a critical section
+pub(crate) trait State: Sized + 'static {
+    /// What a task asks for, such as shared or exclusive access
+    type Want: Copy;
+
+    /// Gives a task what it asks for if it can have it straight away, returning false if it has to wait
+    fn take(inner: &mut Inner<Self>, task: Task, want: Self::Want) -> bool;
+
+    /// Gives up everything a killed task held, handing it on to the tasks waiting. The task has already
+    /// been taken out of the queue, and `granted` is true if it had been handed what it was waiting for.
+    fn abandon(inner: &mut Inner<Self>, task: Task, granted: bool);
+
+    /// Gets the task the waiters lend their priority to, if the primitive has a single holder
+    fn holder(&self) -> Option<Task> {
+        None
+    }
+}
+
+/// A task in the queue
+pub(crate) struct Waiter<W> {
+    /// The waiting task
+    pub(crate) task: Task,
+    /// What the task asked for
+    pub(crate) want: W,
+    /// Whether the task has been handed what it asked for, which it picks up once it runs again
+    pub(crate) granted: bool,
+}
+
+/// Bookkeeping for the runtime, done once the state is no longer borrowed
+enum Pending {
+    /// A task took the primitive
+    Hold(Task),
+    /// A task gave up the primitive
+    Unhold(Task),
+    /// A task was handed what it was waiting for
+    Wake(Task),
+}
+
+/// A primitive's state together with the tasks waiting on it
+pub(crate) struct Inner<S: State> {
+    /// The primitive's own state
+    pub(crate) state: S,
+    /// The waiting tasks, oldest first
+    pub(crate) waiters: VecDeque<Waiter<S::Want>>,
+    /// What to tell the runtime once the state is unlocked
+    pending: Vec<Pending>,
+}
+
+impl<S: State> Inner<S> {
+    /// Gets the position of the longest waiting task that has not been handed anything yet
+    pub(crate) fn first_waiting(&self) -> Option<usize> {
+        self.waiters.iter().position(|waiter| !waiter.granted)
+    }
+
+    /// Hands the task at a position in the queue what it asked for, waking it once the state is unlocked
+    pub(crate) fn grant(&mut self, position: usize) {
+        let waiter = &mut self.waiters[position];
+        waiter.granted = true;
+        self.pending.push(Pending::Wake(waiter.task));
+    }
+
+    /// Records that a task took the primitive, so that it is given up if the task is killed
+    pub(crate) fn hold(&mut self, task: Task) {
+        self.pending.push(Pending::Hold(task));
+    }
+
+    /// Records that a task gave up the primitive
+    pub(crate) fn unhold(&mut self, task: Task) {
+        self.pending.push(Pending::Unhold(task));
+    }
+}
+
+/// A primitive's state and the tasks waiting on it, only accessed inside critical sections
+pub(crate) struct WaitQueue<S: State> {
+    /// The state and queue
+    inner: UnsafeCell<Inner<S>>,
+    /// Makes the signal waiting tasks are woken with from the queue's address
+    signal: fn(usize) -> WakeupSignal,
+}
+
+impl<S: State> WaitQueue<S> {
+    /// Creates an empty queue around a primitive's state
+    pub(crate) const fn new(state: S, signal: fn(usize) -> WakeupSignal) -> WaitQueue<S> {
+        WaitQueue {
+            inner: UnsafeCell::new(Inner { state, waiters: VecDeque::new(), pending: Vec::new() }),
+            signal,
+        }
+    }
+
+    /// The signal tasks waiting in this queue are woken with
+    fn signal(&self) -> WakeupSignal {
+        (self.signal)(self as *const Self as usize)
+    }
+
+    /// Runs `f` on the state and queue inside a critical section, then wakes any tasks it handed something to
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut Inner<S>) -> R) -> R {
+        self.run(f, true)
+    }
+
+    /// Runs `f` on the state and queue, then tells the runtime what changed. Waking may switch to a woken task
+    /// straight away, which may use the queue, so this happens once the state is no longer borrowed.
+    fn run<R>(&self, f: impl FnOnce(&mut Inner<S>) -> R, switch: bool) -> R {
+        let _cs = CriticalSection::enter();
+
+        let (result, pending, holders) = {
+            let inner = unsafe { &mut *self.inner.get() };
+            let before = inner.state.holder();
+            let result = f(inner);
+            (result, core::mem::take(&mut inner.pending), [before, inner.state.holder()])
+        };
+
+        let resource = ResourceRef::new(self);
+        for pending in pending.iter() {
+            match *pending {
+                Pending::Hold(task) => crate::RUNTIME.hold(task, resource),
+                Pending::Unhold(task) => crate::RUNTIME.unhold(task, resource),
+                Pending::Wake(_) => {}
+            }
+        }
+
+        // Everyone who took or gave up the primitive, or whose waiters changed, gets the right
+        // priority before anyone is woken, so that the woken tasks only run if they should
+        let changed = pending.iter().filter_map(|pending| match *pending {
+            Pending::Hold(task) | Pending::Unhold(task) => Some(task),
+            Pending::Wake(_) => None,
+        });
+        for task in holders.into_iter().flatten().chain(changed) {
+            crate::RUNTIME.update_priority(task);
+        }
+
+        for pending in pending {
+            if let Pending::Wake(task) = pending {
+                if switch {
+                    crate::RUNTIME.wake(task.id, self.signal());
+                } else {
+                    crate::RUNTIME.wake_without_switch(task.id, self.signal());
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Waits until the current task is handed what it asks for, giving up and returning false once
+    /// the system time (in milliseconds) reaches the deadline if there is one
+    pub(crate) fn wait(&self, want: S::Want, deadline: Option<u32>) -> bool {
+        let task = crate::RUNTIME.current();
+
+        loop {
+            // Checking and going to sleep happen in one critical section,
+            // so nothing can be handed over in between and leave us asleep
+            let _cs = CriticalSection::enter();
+
+            let done = self.with(|inner| {
+                match inner.waiters.iter().position(|waiter| waiter.task == task) {
+                    // We were handed what we asked for while we slept
+                    Some(position) if inner.waiters[position].granted => {
+                        inner.waiters.remove(position);
+                        return Some(true);
+                    }
+                    Some(_) => {}
+                    None => {
+                        if S::take(inner, task, want) {
+                            return Some(true);
+                        }
+
+                        inner.waiters.push_back(Waiter { task, want, granted: false });
+                    }
+                }
+
+                // Give up once the deadline has passed, leaving the queue so nothing is handed to us
+                if deadline.is_some_and(|deadline| hal().time_ms() >= deadline) {
+                    inner.waiters.retain(|waiter| waiter.task != task);
+                    return Some(false);
+                }
+
+                None
+            });
+
+            if let Some(done) = done {
+                crate::RUNTIME.wait_in(task, None);
+                return done;
+            }
+
+            crate::RUNTIME.wait_in(task, Some(ResourceRef::new(self)));
+            match deadline {
+                Some(deadline) => crate::RUNTIME.await_wake_until(self.signal(), deadline),
+                None => crate::RUNTIME.await_wake(self.signal()),
+            }
+        }
+    }
+}
+
+impl<S: State> Resource for WaitQueue<S> {
+    fn abandon(&self, task: Task) {
+        self.run(|inner| {
+            let granted = match inner.waiters.iter().position(|waiter| waiter.task == task) {
+                Some(position) => inner.waiters.remove(position).is_some_and(|waiter| waiter.granted),
+                None => false,
+            };
+
+            S::abandon(inner, task, granted);
+        }, false);
+    }
+
+    fn holder(&self) -> Option<Task> {
+        unsafe { &*self.inner.get() }.state.holder()
+    }
+
+    fn waiter_priority(&self) -> Option<u8> {
+        let inner = unsafe { &*self.inner.get() };
+
+        // Only a single holder can be lent priority
+        inner.state.holder()?;
+        inner.waiters.iter()
+            .filter(|waiter| !waiter.granted)
+            .filter_map(|waiter| crate::RUNTIME.priority_of(waiter.task))
+            .max()
+    }
+}
+
+impl<S: State> Drop for WaitQueue<S> {
+    /// Makes sure no thread keeps a record of the queue, which a leaked guard would leave behind
+    fn drop(&mut self) {
+        crate::RUNTIME.forget_resource(ResourceRef::new(self));
+    }
+}
+
+// The state and queue are only touched inside critical sections
+unsafe impl<S: State> Send for WaitQueue<S> {}
+unsafe impl<S: State> Sync for WaitQueue<S> {}